@@ -6,6 +6,7 @@ use shared::glam::{vec3, vec4};
 use shared::spirv_std::num_traits::Float;
 use shared::spirv_std::{self, Sampler};
 use shared::spirv_std::{spirv, Image, RuntimeArray};
+use shared::{flags, render_mode, CONE_TILE_SIZE};
 use spirv_std::glam::{IVec2, UVec2, UVec3, Vec2, Vec3, Vec3Swizzles, Vec4, Vec4Swizzles};
 
 //ULTRA VIOLET
@@ -39,10 +40,119 @@ fn calc_normal(at: Vec3, offset: Vec3) -> Vec3 {
         + vec3(1.0, 1.0, 1.0) * eval_sdf(at + (H * vec3(1.0, 1.0, 1.0)), offset))
     .normalize()
 }
+
+///Ambient occlusion at `at`, estimated by sampling the distance field along the normal.
+#[cfg(all(feature = "ao", not(feature = "high_quality")))]
+fn ambient_occlusion(at: Vec3, nrm: Vec3, offset: Vec3) -> f32 {
+    eval_sdf(at + nrm * 0.2, offset) / 0.2
+}
+
+#[cfg(feature = "high_quality")]
+fn ambient_occlusion(at: Vec3, nrm: Vec3, offset: Vec3) -> f32 {
+    let mut occlusion = 0.0;
+    let mut weight = 1.0;
+    let mut i = 1;
+    while i <= 5 {
+        let h = 0.04 * i as f32;
+        occlusion += (h - eval_sdf(at + nrm * h, offset)) * weight;
+        weight *= 0.75;
+        i += 1;
+    }
+    (1.0 - 3.0 * occlusion).clamp(0.0, 1.0)
+}
+
+#[cfg(not(feature = "ao"))]
+fn ambient_occlusion(_at: Vec3, _nrm: Vec3, _offset: Vec3) -> f32 {
+    1.0
+}
+
+///Blue to green to red ramp for `x` in 0..1.
+fn heat(x: f32) -> Vec3 {
+    let x = x.clamp(0.0, 1.0);
+    if x < 0.5 {
+        Vec3::Z.lerp(Vec3::Y, x * 2.0)
+    } else {
+        Vec3::Y.lerp(Vec3::X, x * 2.0 - 1.0)
+    }
+}
+
+///Soft shadow toward `light_dir`, 0 if fully shadowed. Uses the penumbra estimate `k * h / t` described
+/// here: https://iquilezles.org/articles/rmshadows/
+fn soft_shadow(at: Vec3, nrm: Vec3, light_dir: Vec3, push: &shared::RenderUniform) -> f32 {
+    //Offset from the surface, so the shadow ray doesn't hit the surface it starts on.
+    const SHADOW_BIAS: f32 = 0.01;
+    //Shadow rays only need a rough estimate, so they get far fewer steps than primary rays.
+    const MAX_SHADOW_STEPS: u32 = 64;
+    //Below this the point is treated as fully shadowed, further steps would not change the result visibly.
+    const SHADOW_CUTOFF: f32 = 0.001;
+    let k = 1.0 / push.shadow_softness.max(0.0001);
+    let origin = at + nrm * SHADOW_BIAS;
+
+    let mut shadow = 1.0f32;
+    let mut t = SHADOW_BIAS;
+    let mut i = 0;
+    while t < push.max_t && i < push.max_steps.min(MAX_SHADOW_STEPS) {
+        let h = eval_sdf(origin + light_dir * t, Vec3::from(push.offset));
+        if h <= push.epsilon {
+            return 0.0;
+        }
+        shadow = shadow.min(k * h / t);
+        if shadow < SHADOW_CUTOFF {
+            return 0.0;
+        }
+        t += h;
+        i += 1;
+    }
+    shadow.clamp(0.0, 1.0)
+}
+
 fn fresnel(u: f32, f0: Vec3) -> Vec3 {
     f0 + (Vec3::ONE - f0) * (1.0 - u).powf(5.0)
 }
 
+///Cone-traces one ray per [CONE_TILE_SIZE] tile, and writes the distance up to which none of the tile's rays can
+/// hit the surface to `start_image`.
+#[spirv(compute(threads(8, 8, 1)))]
+pub fn cone_prepass(
+    #[spirv(push_constant)] push: &shared::RenderUniform,
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(descriptor_set = 1, binding = 0)] rgbaf32_images: &RuntimeArray<
+        Image!(2D, format = rgba32f, sampled = false),
+    >,
+) {
+    let tile = id.xy();
+    let resolution = UVec2::new(push.resolution[0], push.resolution[1]);
+    let tiles = (resolution + (CONE_TILE_SIZE - 1)) / CONE_TILE_SIZE;
+    if tile.x >= tiles.x || tile.y >= tiles.y || !push.start_image.is_valid() {
+        return;
+    }
+
+    let center = (tile * CONE_TILE_SIZE).as_vec2() + (CONE_TILE_SIZE as f32 - 1.0) / 2.0;
+    let ndc = center / resolution.as_vec2() * 2.0 - 1.0;
+    let ray = push.ray_from_ndc(ndc);
+    //Radius of the cone per unit of distance, so that it covers the tile's corners.
+    let cone = push.pixel_angle() * CONE_TILE_SIZE as f32 * core::f32::consts::FRAC_1_SQRT_2;
+
+    let mut t = 0.001f32;
+    let mut i = 0;
+    while t < ray.max_t && i < push.max_steps {
+        let res = eval_sdf(ray.at(t), Vec3::from(push.offset));
+        let radius = t * cone;
+        if res <= radius {
+            break;
+        }
+        //Largest step after which the cone is still within the unbounding sphere.
+        t += (res - radius) / (1.0 + cone);
+        i += 1;
+    }
+
+    unsafe {
+        rgbaf32_images
+            .index(push.start_image.index() as usize)
+            .write(tile, Vec4::splat(t));
+    }
+}
+
 #[spirv(compute(threads(8, 8, 1)))]
 pub fn renderer(
     #[spirv(push_constant)] push: &shared::RenderUniform,
@@ -62,24 +172,80 @@ pub fn renderer(
     let ray = push.ray_from_ndc(ndc);
 
     let mut t = 0.001f32;
+    if push.flags & flags::CONE_PREPASS != 0 && push.start_image.is_valid() {
+        let start: Vec4 = rgbaf32_images
+            .index(push.start_image.index() as usize)
+            .read(coord / CONE_TILE_SIZE);
+        t = t.max(start.x);
+    }
     let mut i = 0;
-    const EPS: f32 = 0.0001;
-    const MAX_I: usize = 1_000_000;
+    //Scales the runtime epsilon of the uniform.
+    #[cfg(not(feature = "high_quality"))]
+    const EPS_SCALE: f32 = 1.0;
+    #[cfg(feature = "high_quality")]
+    const EPS_SCALE: f32 = 0.1;
+    //Number of steps that maps to red in the step count view.
+    const STEP_HEATMAP_RANGE: f32 = 128.0;
 
-    while t < ray.max_t && i < MAX_I {
-        let res = eval_sdf(ray.at(t), Vec3::from(push.offset));
-        if res <= EPS {
+    //Over-relaxed sphere tracing as described in "Enhanced Sphere Tracing" by Keinert et al.
+    let mut omega = if push.flags & flags::RELAXED != 0 {
+        push.relaxation
+    } else {
+        1.0
+    };
+    let mut prev_radius = 0.0;
+    let mut step = 0.0;
+
+    let mut res = ray.max_t;
+    let mut eps = push.surface_epsilon(t) * EPS_SCALE;
+    while t < ray.max_t && i < push.max_steps {
+        res = eval_sdf(ray.at(t), Vec3::from(push.offset));
+        if omega > 1.0 && res.abs() + prev_radius < step {
+            //The unbounding spheres don't overlap, so the relaxed step might have skipped a surface. Go back to
+            // where the previous, unrelaxed step would have ended and continue without relaxation.
+            t -= step - prev_radius;
+            omega = 1.0;
+        } else if res <= eps {
             break;
         } else {
-            t += res;
+            step = res * omega;
+            prev_radius = res;
+            t += step;
         }
+        eps = push.surface_epsilon(t) * EPS_SCALE;
         i += 1;
     }
 
+    if push.render_mode != render_mode::SHADED {
+        let hit = res <= eps;
+        let exceeded = i >= push.max_steps;
+        let color = match push.render_mode {
+            render_mode::NORMALS if hit => {
+                calc_normal(ray.at(t), Vec3::from(push.offset)) * 0.5 + 0.5
+            }
+            render_mode::DEPTH if hit => Vec3::splat((t / ray.max_t).clamp(0.0, 1.0)),
+            render_mode::DEPTH => Vec3::ONE,
+            render_mode::STEPS => heat(i as f32 / STEP_HEATMAP_RANGE),
+            render_mode::DISTANCE_ERROR if hit || exceeded => heat(res.abs() / eps),
+            render_mode::HIT_CLASS if hit => Vec3::Y,
+            render_mode::HIT_CLASS if exceeded => Vec3::X,
+            render_mode::HIT_CLASS => Vec3::Z,
+            _ => Vec3::ZERO,
+        };
+        if push.target_image.is_valid() {
+            unsafe {
+                rgbaf32_images
+                    .index(push.target_image.index() as usize)
+                    .write(coord, color.extend(1.0));
+            }
+        }
+        return;
+    }
+
     let fog_base = (t / ray.max_t).clamp(0.0, 1.0);
     let fog_color = FOG_COLOR;
 
-    if i >= MAX_I {
+    if i >= push.max_steps {
         unsafe {
             rgbaf32_images
                 .index(push.target_image.index() as usize)
@@ -100,6 +266,16 @@ pub fn renderer(
     }
 
     let nrm = calc_normal(ray.at(t), Vec3::from(push.offset));
+
+    if cfg!(feature = "debug_renderer") {
+        unsafe {
+            rgbaf32_images
+                .index(push.target_image.index() as usize)
+                .write(coord, (nrm * 0.5 + 0.5).extend(1.0));
+        }
+        return;
+    }
+
     //NOTE: Flipping cause we are in Vulkan space with -Y == UP.
     const LIGHT_DIR: Vec3 = vec3(1.0, -1.0, 1.0);
 
@@ -112,11 +288,16 @@ pub fn renderer(
     let base_color = vec3(228.0 / 255.0, 232.0 / 255.0, 230.0 / 255.0);
 
     let n_dot_l = LIGHT_DIR.dot(nrm);
-    let ao = eval_sdf(ray.at(t) + nrm * 0.2, Vec3::from(push.offset));
+    let ao = ambient_occlusion(ray.at(t), nrm, Vec3::from(push.offset));
+    let shadow = if push.flags & flags::SOFT_SHADOWS != 0 && n_dot_l > 0.0 {
+        soft_shadow(ray.at(t), nrm, LIGHT_DIR.normalize(), push)
+    } else {
+        1.0
+    };
 
     let rim_light = Vec3::splat(1.0 - nrm.dot(-ray.direction)) * base_color * 0.2;
-    let direct_light = Vec3::new(1.0, 1.0, 1.0) * n_dot_l.max(0.1);
-    let color = base_color * (direct_light + rim_light) * (ao / 0.2);
+    let direct_light = Vec3::new(1.0, 1.0, 1.0) * (n_dot_l * shadow).max(0.1);
+    let color = base_color * (direct_light + rim_light) * ao;
     let color = color.lerp(FOG_COLOR, fog_base);
 
     if push.target_image.is_valid() {
@@ -0,0 +1,118 @@
+use hotwatch::{notify::EventKind, Event, Hotwatch};
+use sdf_patcher::ENTRY_POINT;
+use shader_builder::{variants::Variant, ShaderBuild};
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, Sender},
+    time::{Duration, Instant},
+};
+
+///Rebuilds a variant of the base shader crate with spirv-builder whenever one of its sources changes.
+///
+/// Watches the crate itself, and the `shared` crate next to it. The build runs on its own thread, since
+/// it takes a few seconds. Needs the toolchain of `rust-toolchain.toml` at runtime.
+pub struct BaseShaderBuilder {
+    ///Only kept alive, dropping it stops watching.
+    _hotwatch: Hotwatch,
+    variant: &'static Variant,
+    builds: Receiver<Vec<u8>>,
+}
+
+impl BaseShaderBuilder {
+    ///Builds `variant` of `shader_crate` on every change. Successful builds are returned by [Self::newest_build].
+    pub fn new(shader_crate: &Path, variant: &'static Variant) -> Result<Self, hotwatch::Error> {
+        let shader_crate = shader_crate.canonicalize()?;
+        let mut hotwatch = Hotwatch::new_with_custom_delay(Duration::from_millis(500))?;
+        let (wake, wake_recv) = std::sync::mpsc::channel();
+
+        let shared = shader_crate.with_file_name("shared");
+        for dir in [shader_crate.clone(), shared] {
+            if !dir.exists() {
+                continue;
+            }
+
+            let wake = wake.clone();
+            hotwatch.watch(&dir, move |ev: Event| {
+                let is_source = ev.paths.iter().any(|p| {
+                    !p.components().any(|c| c.as_os_str() == "target")
+                        && p.extension()
+                            .map(|ext| ext == "rs" || ext == "toml")
+                            .unwrap_or(false)
+                });
+                if is_source
+                    && matches!(
+                        ev.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                    )
+                {
+                    let _ = wake.send(());
+                }
+            })?;
+        }
+
+        let (send, builds) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("base-shader-builder".to_owned())
+            .spawn(move || build_worker(shader_crate, variant, wake_recv, send))
+            .expect("Could not spawn base shader builder!");
+
+        Ok(BaseShaderBuilder {
+            _hotwatch: hotwatch,
+            variant,
+            builds,
+        })
+    }
+
+    ///The variant that is rebuilt.
+    pub fn variant(&self) -> &'static Variant {
+        self.variant
+    }
+
+    ///SPIR-V code of the newest build that was not returned yet.
+    pub fn newest_build(&self) -> Option<Vec<u8>> {
+        self.builds.try_iter().last()
+    }
+}
+
+fn build_worker(
+    shader_crate: PathBuf,
+    variant: &'static Variant,
+    wake: Receiver<()>,
+    send: Sender<Vec<u8>>,
+) {
+    while wake.recv().is_ok() {
+        while wake.try_recv().is_ok() {}
+
+        println!(
+            "Rebuilding base shader {shader_crate:?} variant {}",
+            variant.name
+        );
+        let start = Instant::now();
+        match build(&shader_crate, variant) {
+            Ok(code) => {
+                println!(
+                    "Rebuilt base shader in {:.2}s",
+                    start.elapsed().as_secs_f32()
+                );
+                if send.send(code).is_err() {
+                    return;
+                }
+            }
+            Err(e) => log::error!("Failed to rebuild base shader: {e}"),
+        }
+    }
+}
+
+///Builds `variant` of `shader_crate` with the same configuration `shader_builder` uses.
+fn build(shader_crate: &Path, variant: &Variant) -> Result<Vec<u8>, String> {
+    let modules = ShaderBuild::new(shader_crate, variant.output_name())
+        .with_default_features(variant.default_features)
+        .with_features(variant.features.iter().map(|f| f.to_string()).collect())
+        .build()
+        .map_err(|e| e.to_string())?;
+    let module = modules
+        .iter()
+        .find(|m| m.entry_point.is_none() || m.entry_point.as_deref() == Some(ENTRY_POINT))
+        .ok_or_else(|| format!("no module for entry point {ENTRY_POINT}"))?;
+    std::fs::read(&module.path).map_err(|e| format!("could not read {:?}: {e}", module.path))
+}
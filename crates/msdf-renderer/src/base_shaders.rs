@@ -0,0 +1,129 @@
+use sdf_patcher::BASE_SHADER_VARIANTS;
+use shader_builder::reflect::PushConstantLayout;
+use shared::RenderUniform;
+use std::path::Path;
+
+///A base shader the sdf file can be patched into.
+pub struct BaseShader {
+    pub name: String,
+    pub code: Vec<u8>,
+}
+
+///All loaded base shaders. The built-in variants are always available as fallback.
+pub struct BaseShaders {
+    shaders: Vec<BaseShader>,
+    selected: usize,
+}
+
+impl BaseShaders {
+    ///Loads all built-in variants and `files`. Selects the first file that could be loaded, or the built-in
+    /// `variant` if there is none. Invalid files are skipped.
+    pub fn load(variant: &str, files: &[impl AsRef<Path>]) -> Self {
+        let mut shaders = Vec::with_capacity(BASE_SHADER_VARIANTS.len() + files.len());
+        for (name, code) in BASE_SHADER_VARIANTS {
+            if let Err(e) = check_push_constants(code) {
+                log::error!(
+                    "Push constants of the built-in base shader {name} do not match RenderUniform:\n{e}"
+                );
+            }
+            shaders.push(BaseShader {
+                name: Self::builtin_name(name),
+                code: code.to_vec(),
+            });
+        }
+
+        for file in files {
+            let file = file.as_ref();
+            let code = match std::fs::read(file) {
+                Ok(code) => code,
+                Err(e) => {
+                    log::error!("Could not read base shader {file:?}: {e}");
+                    continue;
+                }
+            };
+            if let Err(e) = check(&code) {
+                log::error!("Ignoring base shader {file:?}: {e}");
+                continue;
+            }
+
+            shaders.push(BaseShader {
+                name: file.to_string_lossy().into_owned(),
+                code,
+            });
+        }
+
+        let selected = if shaders.len() > BASE_SHADER_VARIANTS.len() {
+            BASE_SHADER_VARIANTS.len()
+        } else {
+            BASE_SHADER_VARIANTS
+                .iter()
+                .position(|(name, _)| *name == variant)
+                .unwrap_or_else(|| {
+                    log::error!("No built-in base shader variant {variant}, using the default one");
+                    0
+                })
+        };
+        BaseShaders { shaders, selected }
+    }
+
+    ///Name of the built-in `variant`.
+    pub fn builtin_name(variant: &str) -> String {
+        format!("built-in {variant}")
+    }
+
+    ///Replaces the code of the base shader called `name`, if `code` passes the same checks as loaded files.
+    /// Returns its index.
+    #[cfg(feature = "hot-reload")]
+    pub fn replace(&mut self, name: &str, code: Vec<u8>) -> Result<usize, String> {
+        check(&code)?;
+        let index = self
+            .shaders
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| format!("no base shader called {name}"))?;
+        self.shaders[index].code = code;
+        Ok(index)
+    }
+
+    pub fn get(&self, index: usize) -> &BaseShader {
+        &self.shaders[index]
+    }
+
+    pub fn selected(&self) -> &BaseShader {
+        self.get(self.selected)
+    }
+
+    pub fn selected_index(&self) -> usize {
+        self.selected
+    }
+
+    ///Index of the base shader after `index`, if there is another one.
+    pub fn next(&self, index: usize) -> Option<usize> {
+        if self.shaders.len() < 2 {
+            return None;
+        }
+        Some((index + 1) % self.shaders.len())
+    }
+
+    ///Selects the base shader at `index`. Should only be called once the patch pipeline accepted it.
+    pub fn select(&mut self, index: usize) {
+        self.selected = index;
+    }
+}
+
+///Checks that `code` can be patched, and that its push constants match [RenderUniform].
+fn check(code: &[u8]) -> Result<(), String> {
+    sdf_patcher::check_base_shader(code).map_err(|e| e.to_string())?;
+    check_push_constants(code)
+        .map_err(|e| format!("push constants do not match RenderUniform:\n{e}"))
+}
+
+///Checks that the push constant block of `code` matches [RenderUniform].
+fn check_push_constants(code: &[u8]) -> Result<(), String> {
+    let words = sdf_patcher::words_from_bytes(code)
+        .ok_or_else(|| "size is not a multiple of 4 bytes".to_owned())?;
+    match PushConstantLayout::reflect(&words)? {
+        Some(layout) => layout.check(&RenderUniform::field_layout()),
+        None => Err("module has no push constants".to_owned()),
+    }
+}
@@ -1,6 +1,6 @@
 use std::time::Instant;
 
-use shared::glam::{EulerRot, Quat, Vec3};
+use shared::glam::{EulerRot, Quat, Vec2, Vec3};
 use winit::event::{DeviceEvent, ElementState, Event, VirtualKeyCode, WindowEvent};
 
 #[derive(Clone, Copy)]
@@ -52,6 +52,21 @@ impl Default for Camera {
 
 impl Camera {
     const CAM_SPEEDUP: f32 = 0.001;
+
+    ///Creates a camera at `location`, rotated by `yaw_pitch` (in degrees).
+    pub fn new(location: Vec3, yaw_pitch: Vec2) -> Self {
+        Camera {
+            location,
+            rotation: Quat::from_euler(
+                EulerRot::YXZ,
+                yaw_pitch.x.to_radians(),
+                yaw_pitch.y.to_radians(),
+                0.0,
+            ),
+            ..Default::default()
+        }
+    }
+
     pub fn on_event(&mut self, event: &winit::event::Event<()>) {
         match event {
             Event::WindowEvent {
@@ -0,0 +1,211 @@
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use marpii::ash::vk;
+use sdf_patcher::Route;
+use shared::{
+    glam::{Vec2, Vec3},
+    render_mode,
+};
+
+use crate::templates::Template;
+
+///Command line arguments of the renderer.
+#[derive(Parser, Debug, Clone)]
+#[command(version, about = "Live-patching MiniSdf sphere-trace renderer.")]
+pub struct Args {
+    ///The .minisdf file that is watched and patched into the renderer.
+    #[arg(default_value = "sdf.minisdf")]
+    pub file: PathBuf,
+
+    ///Template that is written to `file`, if the file does not exist yet.
+    #[arg(long, value_enum)]
+    pub template: Option<Template>,
+
+    ///Routes a field to a patch point of the base shader, formatted as `field=patch_point`. Can be used multiple times.
+    #[arg(long = "route")]
+    pub routes: Vec<Route>,
+
+    ///Built-in base shader variant that is used, if no `--base-shader` is given. `B` switches between all variants.
+    #[arg(long, default_value = "default", value_parser = variant_names())]
+    pub variant: String,
+
+    ///SPIR-V base shader to patch the file into, instead of the built-in one. Needs a `renderer` compute entry point and
+    /// an `eval_sdf` function. Can be used multiple times, the first one is used at startup. `B` switches between them.
+    #[arg(long = "base-shader")]
+    pub base_shaders: Vec<PathBuf>,
+
+    ///Directory patched shaders are cached in. Defaults to the user's cache directory.
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+
+    ///Disables the patched shader cache.
+    #[arg(long)]
+    pub no_cache: bool,
+
+    ///Removes all cached shaders before starting.
+    #[arg(long)]
+    pub clear_cache: bool,
+
+    ///Watches the base shader crate at this path (for instance `crates/base-shader`), rebuilds it on changes and
+    /// patches the sdf file into the new base shader.
+    #[cfg(feature = "hot-reload")]
+    #[arg(long)]
+    pub base_shader_crate: Option<PathBuf>,
+
+    ///Number of successfully patched shaders that are kept in the history.
+    #[arg(long, default_value_t = 16)]
+    pub history_length: usize,
+
+    ///Initial window width in pixels.
+    #[arg(long, default_value_t = 1280)]
+    pub width: u32,
+
+    ///Initial window height in pixels.
+    #[arg(long, default_value_t = 720)]
+    pub height: u32,
+
+    ///Log level of the renderer (off, error, warn, info, debug, trace).
+    #[arg(long, default_value = "warn")]
+    pub log_level: log::LevelFilter,
+
+    ///Swapchain present mode. Uses the swapchain's default if not set.
+    #[arg(long, value_enum)]
+    pub present_mode: Option<PresentMode>,
+
+    ///Startup camera location, formatted as `x,y,z`.
+    #[arg(long, value_parser = parse_vec3, default_value = "0,0,-3", allow_hyphen_values = true)]
+    pub camera_pos: Vec3,
+
+    ///Startup camera rotation as `yaw,pitch` in degrees.
+    #[arg(long, value_parser = parse_vec2, default_value = "0,0", allow_hyphen_values = true)]
+    pub camera_rotation: Vec2,
+
+    ///Startup camera field of view in degrees.
+    #[arg(long, default_value_t = 90.0)]
+    pub fov: f32,
+
+    ///Startup value of the field's `offset` parameter, formatted as `x,y,z`.
+    #[arg(long, value_parser = parse_vec3, default_value = "0,0,0", allow_hyphen_values = true)]
+    pub offset: Vec3,
+
+    ///Startup render mode. `M`/`N` cycle through all modes.
+    #[arg(long, value_enum, default_value_t = RenderMode::Shaded)]
+    pub render_mode: RenderMode,
+
+    ///Distance to the surface at which a ray counts as hit. `F1`/`F2` halve/double it at runtime.
+    #[arg(long, default_value_t = 0.0001)]
+    pub epsilon: f32,
+
+    ///Step budget of a ray. `F3`/`F4` halve/double it at runtime.
+    #[arg(long, default_value_t = 1_000_000)]
+    pub max_steps: u32,
+
+    ///Distance after which a ray counts as miss. `F5`/`F6` halve/double it at runtime.
+    #[arg(long, default_value_t = 150.0)]
+    pub max_distance: f32,
+
+    ///Grows the epsilon with the footprint of a pixel, so distant geometry stops tracing sooner. `F7` toggles
+    /// it at runtime.
+    #[arg(long)]
+    pub pixel_epsilon: bool,
+
+    ///Uses over-relaxed sphere tracing, which needs fewer steps in open scenes. `F8` toggles it at runtime.
+    #[arg(long)]
+    pub relaxed: bool,
+
+    ///Step scale of over-relaxed sphere tracing, in 1..2.
+    #[arg(long, default_value_t = 1.6)]
+    pub relaxation: f32,
+
+    ///Cone-traces a coarse tile grid before the main pass, so rays start closer to the surface. `F9` toggles it
+    /// at runtime.
+    #[arg(long)]
+    pub cone_prepass: bool,
+
+    ///Traces soft shadows toward the light. `F10` toggles them at runtime.
+    #[arg(long)]
+    pub soft_shadows: bool,
+
+    ///Size of the shadow penumbra, larger values give softer shadows. `F11`/`F12` halve/double it at runtime.
+    #[arg(long, default_value_t = 0.1)]
+    pub shadow_softness: f32,
+}
+
+fn variant_names() -> clap::builder::PossibleValuesParser {
+    sdf_patcher::BASE_SHADER_VARIANTS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .into()
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresentMode {
+    Immediate,
+    Mailbox,
+    Fifo,
+    FifoRelaxed,
+}
+
+impl From<PresentMode> for vk::PresentModeKHR {
+    fn from(value: PresentMode) -> Self {
+        match value {
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::FifoRelaxed => vk::PresentModeKHR::FIFO_RELAXED,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    Shaded,
+    Normals,
+    Depth,
+    Steps,
+    DistanceError,
+    HitClass,
+}
+
+impl From<RenderMode> for u32 {
+    fn from(value: RenderMode) -> Self {
+        match value {
+            RenderMode::Shaded => render_mode::SHADED,
+            RenderMode::Normals => render_mode::NORMALS,
+            RenderMode::Depth => render_mode::DEPTH,
+            RenderMode::Steps => render_mode::STEPS,
+            RenderMode::DistanceError => render_mode::DISTANCE_ERROR,
+            RenderMode::HitClass => render_mode::HIT_CLASS,
+        }
+    }
+}
+
+fn parse_floats<const N: usize>(s: &str) -> Result<[f32; N], String> {
+    let mut values = [0.0; N];
+    let mut parts = s.split(',');
+    for v in values.iter_mut() {
+        let part = parts
+            .next()
+            .ok_or_else(|| format!("expected {N} comma separated values, got \"{s}\""))?;
+        *v = part
+            .trim()
+            .parse()
+            .map_err(|e| format!("could not parse \"{part}\": {e}"))?;
+    }
+
+    if parts.next().is_some() {
+        return Err(format!("expected {N} comma separated values, got \"{s}\""));
+    }
+
+    Ok(values)
+}
+
+fn parse_vec3(s: &str) -> Result<Vec3, String> {
+    parse_floats::<3>(s).map(Vec3::from)
+}
+
+fn parse_vec2(s: &str) -> Result<Vec2, String> {
+    parse_floats::<2>(s).map(Vec2::from)
+}
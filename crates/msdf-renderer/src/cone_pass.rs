@@ -0,0 +1,176 @@
+use marpii::{
+    ash::vk::{self, Extent2D},
+    resources::{ComputePipeline, ImgDesc, PushConstant},
+};
+use marpii_rmg::{ImageHandle, Rmg, Task};
+use marpii_rmg_shared::ResourceHandle;
+use shared::{RenderUniform, CONE_TILE_SIZE};
+use std::sync::Arc;
+use winit::event::{ElementState, Event, VirtualKeyCode, WindowEvent};
+
+use crate::st_pass::SphereTracing;
+
+///Optional pass before [SphereTracing], that cone-traces a coarse tile grid and writes a conservative start
+/// distance per tile into [Self::start_image].
+///
+/// Uses the pre-pass entry point of the patched module, so it is only available if the base shader has one.
+pub struct ConePrepass {
+    enabled: bool,
+    pipeline: Option<Arc<ComputePipeline>>,
+    pub start_image: ImageHandle,
+    pc: PushConstant<RenderUniform>,
+}
+
+impl ConePrepass {
+    pub fn new(rmg: &mut Rmg, resolution: Extent2D, enabled: bool) -> Self {
+        let tiles = tile_extent(resolution);
+        let start_image = rmg
+            .new_image_uninitialized(
+                ImgDesc::storage_image_2d(
+                    tiles.width,
+                    tiles.height,
+                    vk::Format::R32G32B32A32_SFLOAT,
+                ),
+                Some("cone_prepass_start"),
+            )
+            .unwrap();
+
+        ConePrepass {
+            enabled,
+            pipeline: None,
+            start_image,
+            pc: PushConstant::new(RenderUniform::default(), vk::ShaderStageFlags::COMPUTE),
+        }
+    }
+
+    ///Toggles the pass with `F9`.
+    pub fn on_event(&mut self, event: &Event<()>) {
+        match event {
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput { input, .. },
+                ..
+            } => match (input.virtual_keycode, input.state) {
+                (Some(VirtualKeyCode::F9), ElementState::Released) => {
+                    self.enabled = !self.enabled;
+                    if self.enabled && self.pipeline.is_none() {
+                        log::warn!("Cone pre-pass enabled, but the base shader has none");
+                    } else {
+                        log::warn!("Cone pre-pass: {}", self.enabled);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    ///The start image, if the pass should be recorded this frame.
+    pub fn active_start_image(&self) -> Option<ImageHandle> {
+        if self.enabled && self.pipeline.is_some() {
+            Some(self.start_image.clone())
+        } else {
+            None
+        }
+    }
+
+    ///Takes over the pipeline, uniform and resolution of `st_pass`. Needs to be called after `st_pass` fetched
+    /// its newest pipeline.
+    pub fn update(&mut self, rmg: &mut Rmg, st_pass: &SphereTracing) {
+        self.pipeline = st_pass.prepass_pipeline();
+
+        let resolution = st_pass.target_image.extent_2d();
+        *self.pc.get_content_mut() = *st_pass.uniform();
+        self.pc.get_content_mut().resolution = [resolution.width, resolution.height];
+        self.pc.get_content_mut().target_image = ResourceHandle::INVALID;
+
+        let tiles = tile_extent(resolution);
+        if self.start_image.extent_2d() != tiles {
+            let mut desc = self.start_image.image_desc().clone();
+            desc.extent.width = tiles.width;
+            desc.extent.height = tiles.height;
+            self.start_image = rmg
+                .new_image_uninitialized(desc, Some("cone_prepass_start"))
+                .unwrap();
+        }
+    }
+
+    pub fn dispatch_size(&self) -> [u32; 3] {
+        [
+            (self.start_image.extent_2d().width / 8) + 1,
+            (self.start_image.extent_2d().height / 8) + 1,
+            1,
+        ]
+    }
+}
+
+///Number of [CONE_TILE_SIZE] tiles needed to cover `resolution`.
+fn tile_extent(resolution: Extent2D) -> Extent2D {
+    Extent2D {
+        width: ((resolution.width + CONE_TILE_SIZE - 1) / CONE_TILE_SIZE).max(1),
+        height: ((resolution.height + CONE_TILE_SIZE - 1) / CONE_TILE_SIZE).max(1),
+    }
+}
+
+impl Task for ConePrepass {
+    fn name(&self) -> &'static str {
+        "Cone pre-pass"
+    }
+    fn queue_flags(&self) -> vk::QueueFlags {
+        vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE
+    }
+    fn register(&self, registry: &mut marpii_rmg::ResourceRegistry) {
+        registry
+            .request_image(
+                &self.start_image,
+                vk::PipelineStageFlags2::COMPUTE_SHADER,
+                vk::AccessFlags2::SHADER_STORAGE_WRITE,
+                vk::ImageLayout::GENERAL,
+            )
+            .unwrap();
+
+        if let Some(pipeline) = &self.pipeline {
+            registry.register_asset(pipeline.clone());
+        }
+    }
+
+    fn pre_record(
+        &mut self,
+        resources: &mut marpii_rmg::Resources,
+        _ctx: &marpii_rmg::CtxRmg,
+    ) -> Result<(), marpii_rmg::RecordError> {
+        self.pc.get_content_mut().start_image = resources
+            .resource_handle_or_bind(self.start_image.clone())
+            .unwrap();
+        Ok(())
+    }
+
+    fn record(
+        &mut self,
+        device: &std::sync::Arc<marpii::context::Device>,
+        command_buffer: &vk::CommandBuffer,
+        _resources: &marpii_rmg::Resources,
+    ) {
+        let pipeline = match &self.pipeline {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+
+        unsafe {
+            device.inner.cmd_bind_pipeline(
+                *command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.pipeline,
+            );
+            device.inner.cmd_push_constants(
+                *command_buffer,
+                pipeline.layout.layout,
+                vk::ShaderStageFlags::ALL,
+                0,
+                self.pc.content_as_bytes(),
+            );
+
+            let [dx, dy, dz] = self.dispatch_size();
+            device.inner.cmd_dispatch(*command_buffer, dx, dy, dz);
+        }
+    }
+}
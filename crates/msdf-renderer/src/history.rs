@@ -0,0 +1,143 @@
+use sdf_patcher::ResolvedSource;
+use std::collections::VecDeque;
+
+///A successfully patched shader, and the source it was patched from.
+#[derive(Clone)]
+pub struct HistoryEntry {
+    pub code: Vec<u32>,
+    ///`None` for the unpatched base shader.
+    pub source: Option<ResolvedSource>,
+}
+
+///Ring buffer of the last N successfully patched shaders.
+///
+/// Normally the newest entry is _selected_. Stepping backwards selects older entries, until a new
+/// entry is pushed, or the newest entry is selected again.
+pub struct ShaderHistory {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+    ///Index of the selected entry, `None` if the newest one is selected.
+    selected: Option<usize>,
+}
+
+impl ShaderHistory {
+    pub fn new(capacity: usize) -> Self {
+        ShaderHistory {
+            entries: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            selected: None,
+        }
+    }
+
+    ///Adds a new, newest entry and selects it.
+    ///
+    /// If the code equals the newest entry, for instance because writing a source back re-triggered the patch,
+    /// the newest entry is selected instead of adding a duplicate.
+    pub fn push(&mut self, entry: HistoryEntry) {
+        self.selected = None;
+        if self.entries.back().map(|newest| newest.code == entry.code) == Some(true) {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    fn selected_index(&self) -> usize {
+        self.selected
+            .unwrap_or_else(|| self.entries.len().saturating_sub(1))
+    }
+
+    ///Moves the selection `delta` entries towards the newest (positive) or oldest (negative) entry.
+    /// Returns the newly selected entry, if the selection changed.
+    pub fn step(&mut self, delta: isize) -> Option<&HistoryEntry> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let current = self.selected_index();
+        let newest = self.entries.len() - 1;
+        let next = current.saturating_add_signed(delta).min(newest);
+        if next == current {
+            return None;
+        }
+
+        self.selected = if next == newest { None } else { Some(next) };
+        log::warn!(
+            "Selected shader history entry {} of {}",
+            next + 1,
+            self.entries.len()
+        );
+        self.entries.get(next)
+    }
+
+    pub fn selected(&self) -> Option<&HistoryEntry> {
+        self.entries.get(self.selected_index())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(code: u32) -> HistoryEntry {
+        HistoryEntry {
+            code: vec![code],
+            source: None,
+        }
+    }
+
+    fn selected_code(history: &ShaderHistory) -> u32 {
+        history.selected().unwrap().code[0]
+    }
+
+    #[test]
+    fn evicts_oldest_entries() {
+        let mut history = ShaderHistory::new(3);
+        for code in 0..5 {
+            history.push(entry(code));
+        }
+        assert_eq!(history.entries.len(), 3);
+        assert_eq!(selected_code(&history), 4);
+        assert_eq!(history.step(-10).unwrap().code, vec![2]);
+    }
+
+    #[test]
+    fn clamps_steps_at_both_ends() {
+        let mut history = ShaderHistory::new(4);
+        assert!(history.step(-1).is_none());
+        for code in 0..3 {
+            history.push(entry(code));
+        }
+
+        //Already at the newest entry.
+        assert!(history.step(1).is_none());
+        assert_eq!(selected_code(&history), 2);
+
+        assert_eq!(history.step(-1).unwrap().code, vec![1]);
+        assert_eq!(history.step(-5).unwrap().code, vec![0]);
+        assert!(history.step(-1).is_none());
+        assert_eq!(selected_code(&history), 0);
+
+        assert_eq!(history.step(5).unwrap().code, vec![2]);
+        assert_eq!(history.selected, None);
+    }
+
+    #[test]
+    fn skips_duplicates_of_newest_entry() {
+        let mut history = ShaderHistory::new(4);
+        history.push(entry(0));
+        history.push(entry(1));
+        history.step(-1);
+
+        history.push(entry(1));
+        assert_eq!(history.entries.len(), 2);
+        assert_eq!(selected_code(&history), 1);
+
+        //Older entries are no duplicates, restoring them adds a new entry.
+        history.push(entry(0));
+        assert_eq!(history.entries.len(), 3);
+    }
+}
@@ -1,57 +1,139 @@
 //! A really simple sphere trace renderer.
 //!
-//! uses the `sdf.minisdf` file to runtime-patch the sphere-tracing shader with new code.
+//! uses a `.minisdf` file (`sdf.minisdf` by default, see `--help`) to runtime-patch the sphere-tracing shader with new code.
 //!
 //! This is a test intersection of two projects. The [minisdf]() compiler, and the [spv-patcher]().
 
 use std::time::Instant;
 
+use base_shaders::BaseShaders;
 use camera::Camera;
+use clap::Parser;
+use cone_pass::ConePrepass;
 use marpii::{ash::vk::Extent2D, context::Ctx};
 use marpii_rmg::{Rmg, Task};
 use marpii_rmg_tasks::SwapchainPresent;
 use offset_entity::OffsetEntity;
+use sdf_patcher::{PatchCache, PatchRouting, SdfPatchPipeline};
 use shared::glam::{EulerRot, Quat, Vec3};
 use winit::{
+    dpi::PhysicalSize,
     event::{DeviceEvent, ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
     event_loop::ControlFlow,
+    window::WindowBuilder,
 };
 
+#[cfg(feature = "hot-reload")]
+mod base_builder;
+mod base_shaders;
 mod camera;
+mod cli;
+mod cone_pass;
+mod history;
 mod offset_entity;
 mod patcher;
 mod st_pass;
+mod templates;
+mod watcher;
 
 fn main() {
+    let args = cli::Args::parse();
+
     simple_logger::SimpleLogger::new()
-        .with_level(log::LevelFilter::Warn)
+        .with_level(args.log_level)
         .init()
         .unwrap();
 
+    match args
+        .template
+        .unwrap_or_default()
+        .write_if_missing(&args.file)
+    {
+        Ok(true) => log::warn!("Created {:?} from template", args.file),
+        Ok(false) => {
+            if let Some(template) = args.template {
+                log::warn!(
+                    "{:?} already exists, ignoring template {template:?}",
+                    args.file
+                );
+            }
+        }
+        Err(e) => panic!("Could not create {:?}: {e}", args.file),
+    }
+
+    let cache = PatchCache::new(
+        args.cache_dir
+            .clone()
+            .unwrap_or_else(PatchCache::default_dir),
+    );
+    if args.clear_cache {
+        match cache.clear() {
+            Ok(()) => log::warn!("Cleared shader cache {:?}", cache.dir()),
+            Err(e) => log::error!("Could not clear shader cache {:?}: {e}", cache.dir()),
+        }
+    }
+
+    let base_shaders = BaseShaders::load(&args.variant, &args.base_shaders);
+    log::info!("Using base shader {}", base_shaders.selected().name);
+    let mut pipeline = SdfPatchPipeline::new(&base_shaders.selected().code)
+        .expect("Could not load basecode")
+        .with_routing(PatchRouting::new(args.routes.clone()));
+    if !args.no_cache {
+        pipeline = pipeline.with_cache(cache);
+    }
+
     let ev = winit::event_loop::EventLoop::new();
-    let window = winit::window::Window::new(&ev).unwrap();
+    let window = WindowBuilder::new()
+        .with_title("msdf-renderer")
+        .with_inner_size(PhysicalSize::new(args.width, args.height))
+        .build(&ev)
+        .unwrap();
     let (context, surface) = Ctx::default_with_surface(&window, true).unwrap();
     let mut rmg = Rmg::new(context).unwrap();
 
     let mut present_pass = SwapchainPresent::new(&mut rmg, surface).unwrap();
+    if let Some(present_mode) = args.present_mode {
+        present_pass.set_present_mode(present_mode.into());
+    }
+    let resolution = present_pass.extent().unwrap_or(Extent2D {
+        width: args.width,
+        height: args.height,
+    });
+    let mut cone_pass = ConePrepass::new(&mut rmg, resolution, args.cone_prepass);
     let mut st_pass = st_pass::SphereTracing::new(
         &mut rmg,
-        present_pass.extent().unwrap_or(Extent2D {
-            width: 1,
-            height: 1,
-        }),
+        resolution,
+        &args.file,
+        pipeline,
+        args.history_length,
+        base_shaders,
     );
+    st_pass.fov(args.fov);
+    st_pass.render_mode(args.render_mode.into());
+    st_pass.epsilon(args.epsilon);
+    st_pass.max_steps(args.max_steps);
+    st_pass.max_distance(args.max_distance);
+    st_pass.pixel_epsilon(args.pixel_epsilon);
+    st_pass.relaxed(args.relaxed, args.relaxation);
+    st_pass.soft_shadows(args.soft_shadows, args.shadow_softness);
+    #[cfg(feature = "hot-reload")]
+    if let Some(shader_crate) = &args.base_shader_crate {
+        st_pass.watch_base_shader(shader_crate, &args.variant);
+    }
 
-    let mut camera = Camera::default();
-    let mut offset_entity = OffsetEntity::new();
+    let mut camera = Camera::new(args.camera_pos, args.camera_rotation);
+    let mut offset_entity = OffsetEntity::new(args.offset);
 
     let mut last_fps_draw = Instant::now();
+    let mut last_title = String::new();
 
     ev.run(move |ev, _, cf| {
         *cf = ControlFlow::Poll;
 
         camera.on_event(&ev);
         offset_entity.on_event(&ev);
+        st_pass.on_event(&ev);
+        cone_pass.on_event(&ev);
         match ev {
             Event::RedrawRequested(_wid) => {
                 camera.update();
@@ -69,12 +151,22 @@ fn main() {
                     },
                 );
 
+                //NOTE: The pre-pass uses the same module as the main pass, so the pipeline is updated before either is recorded.
+                st_pass.try_new_pipeline(&rmg.ctx);
+                cone_pass.update(&mut rmg, &st_pass);
+                let start_image = cone_pass.active_start_image();
+                st_pass.start_image(start_image.clone());
+
                 present_pass.push_image(
                     st_pass.target_image.clone(),
                     st_pass.target_image.extent_2d(),
                 );
 
-                rmg.record()
+                let mut recorder = rmg.record();
+                if start_image.is_some() {
+                    recorder = recorder.add_task(&mut cone_pass).unwrap();
+                }
+                recorder
                     .add_task(&mut st_pass)
                     .unwrap()
                     .add_task(&mut present_pass)
@@ -82,18 +174,31 @@ fn main() {
                     .execute()
                     .unwrap();
 
+                //Show the latest patch failure until a patch succeeds again.
+                let title = match st_pass.patch_error() {
+                    Some(e) => format!("msdf-renderer - {e}"),
+                    None => "msdf-renderer".to_owned(),
+                };
+                if title != last_title {
+                    window.set_title(&title);
+                    last_title = title;
+                }
+
                 if last_fps_draw.elapsed().as_millis() > 500 {
                     last_fps_draw = Instant::now();
                     let timing = rmg.get_recent_track_timings();
                     for t in timing {
+                        let timing_ms = t.timing / 1_000_000.0;
                         if &t.name == st_pass.name() {
-                            let timing_ms = t.timing / 1_000_000.0;
                             println!(
-                                "{}: {}ms  aka {}fps",
+                                "{} ({}): {}ms  aka {}fps",
                                 t.name,
+                                st_pass.trace_mode(),
                                 timing_ms,
                                 1.0 / (timing_ms / 1000.0)
                             );
+                        } else if &t.name == cone_pass.name() {
+                            println!("{}: {}ms", t.name, timing_ms);
                         }
                     }
                 }
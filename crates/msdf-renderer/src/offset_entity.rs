@@ -60,9 +60,9 @@ impl OffsetEntity {
         }
     }
 
-    pub fn new() -> Self {
+    pub fn new(offset_parameter: Vec3) -> Self {
         OffsetEntity {
-            offset_parameter: Vec3::ZERO,
+            offset_parameter,
             offset_velocity: Vec3::ZERO,
             last_update: Instant::now(),
         }
@@ -1,151 +1,399 @@
-use hotwatch::{notify::Event, Hotwatch};
 use marpii::{resources::ShaderModule, OoS};
-use patch_function::StaticReplace;
+use sdf_patcher::{
+    has_entry_point, resolve_includes, PatchError, SdfPatchPipeline, CONE_PREPASS_ENTRY_POINT,
+};
+
+#[cfg(feature = "hot-reload")]
+use crate::base_builder::BaseShaderBuilder;
+use crate::{
+    base_shaders::BaseShaders,
+    history::{HistoryEntry, ShaderHistory},
+    watcher::FileWatcher,
+};
+#[cfg(feature = "hot-reload")]
+use shader_builder::variants::Variant;
 use std::{
-    path::Path,
+    path::{Path, PathBuf},
     sync::{
-        mpsc::{Receiver, TryRecvError},
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, Sender, TryRecvError},
         Arc,
     },
     time::{Duration, Instant},
 };
 
-static BASE_SHADER: &'static [u8] = include_bytes!("../../resources/base-shader.spv");
+///A module fetched from the [Patcher].
+pub struct PatchedModule {
+    pub module: OoS<ShaderModule>,
+    ///True if the module has a [CONE_PREPASS_ENTRY_POINT], which custom base shaders might not have.
+    pub has_cone_prepass: bool,
+}
 
-///Hotwatch local patcher that first executes msdfc, then patches that into the
-/// base shader.
-struct LivePatcher {}
+impl PatchedModule {
+    fn new(module: OoS<ShaderModule>, code: &[u32]) -> Self {
+        PatchedModule {
+            module,
+            has_cone_prepass: has_entry_point(code, CONE_PREPASS_ENTRY_POINT),
+        }
+    }
+}
 
-///Patcher utility. Observes the `sdf.minisdf` file, and recompiles the shader if needed.
+///Patcher utility. Observes the given `.minisdf` file, and recompiles the shader if needed.
+///
+/// The actual compile and patch work is done by [SdfPatchPipeline] on a worker thread. The patcher only uploads the result to the device.
+///
+/// Successful patches are kept in a [ShaderHistory], which can be stepped through.
 pub struct Patcher {
-    ///Receiver of new shader modules
-    recv: Receiver<OoS<ShaderModule>>,
-    hotwatch: Hotwatch,
+    ///Receiver of new shader modules, or the error that prevented the last patch.
+    recv: Receiver<Result<(OoS<ShaderModule>, HistoryEntry), PatchError>>,
+    ///Receiver of all files the sdf file depends on, whenever its includes change.
+    dependency_recv: Receiver<Vec<PathBuf>>,
+    ///Receiver of the index of every base shader sent to the worker, and whether the worker accepted it.
+    base_recv: Receiver<(usize, bool)>,
+    watcher: FileWatcher,
+    ///Wakes the compile worker.
+    wake: Sender<WorkerRequest>,
+    ///Counts source and base shader changes.
+    generation: Arc<AtomicU64>,
+    base_shaders: BaseShaders,
+    ///Index of the base shader that was sent to the worker, but not accepted or rejected yet.
+    requested_base: Option<usize>,
+    #[cfg(feature = "hot-reload")]
+    base_builder: Option<BaseShaderBuilder>,
+    last_error: Option<PatchError>,
+    device: Arc<marpii::context::Device>,
+    history: ShaderHistory,
+    ///Module of a history entry that was selected, but not fetched yet.
+    selected_module: Option<PatchedModule>,
 }
 
 impl Patcher {
-    pub fn new(device: Arc<marpii::context::Device>) -> Self {
+    pub fn new(
+        device: Arc<marpii::context::Device>,
+        sdf_file: impl AsRef<Path>,
+        pipeline: SdfPatchPipeline,
+        history_length: usize,
+        base_shaders: BaseShaders,
+    ) -> Self {
+        let sdf_file: PathBuf = sdf_file.as_ref().to_path_buf();
         let (send, recv) = std::sync::mpsc::channel();
 
         //NOTE: PreSend the first shader module, by just loading the base shader without patches.
-        let base_shader = ShaderModule::new_from_bytes(&device, BASE_SHADER)
+        let base_code = &base_shaders.selected().code;
+        let base_shader = ShaderModule::new_from_bytes(&device, base_code)
             .expect("Could not build base-shader module!");
 
-        send.send(OoS::new(base_shader))
-            .expect("Failed to send initial base shader!");
+        send.send(Ok((
+            OoS::new(base_shader),
+            HistoryEntry {
+                code: sdf_patcher::words_from_bytes(base_code)
+                    .expect("Base shader is not made of 32bit words!"),
+                source: None,
+            },
+        )))
+        .expect("Failed to send initial base shader!");
+
+        if !sdf_file.exists() {
+            panic!("File {sdf_file:?} does not exist!");
+        }
 
-        let mut watcher = Hotwatch::new_with_custom_delay(Duration::from_millis(500))
-            .expect("Could not create file watcher!");
+        //Counts source changes. The worker compares it before and after patching to detect outdated results.
+        let generation = Arc::new(AtomicU64::new(0));
+        let (wake, wake_recv) = std::sync::mpsc::channel();
+        let (dependency_send, dependency_recv) = std::sync::mpsc::channel();
+        let (base_send, base_recv) = std::sync::mpsc::channel();
 
-        if !Path::new("sdf.minisdf").exists() {
-            panic!("File sdf.minisdf does not exist!");
+        {
+            let device = device.clone();
+            let sdf_file = sdf_file.clone();
+            let generation = generation.clone();
+            std::thread::Builder::new()
+                .name("sdf-compile-worker".to_owned())
+                .spawn(move || {
+                    compile_worker(
+                        pipeline,
+                        device,
+                        sdf_file,
+                        generation,
+                        wake_recv,
+                        send,
+                        dependency_send,
+                        base_send,
+                    )
+                })
+                .expect("Could not spawn compile worker!");
         }
 
-        //touch the file
+        //Patch the current file once, so we don't have to wait for the first change.
+        let _ = wake.send(WorkerRequest::Patch);
 
-        let mut patcher =
-            spv_patcher::Module::new(BASE_SHADER.to_vec()).expect("Could not load basecode");
+        let watcher = {
+            let (wake, generation) = (wake.clone(), generation.clone());
+            FileWatcher::new(&sdf_file, Duration::from_millis(500), move || {
+                generation.fetch_add(1, Ordering::AcqRel);
+                let _ = wake.send(WorkerRequest::Patch);
+            })
+            .expect("Could not schedule sdf-file watcher!")
+        };
 
-        watcher
-            .watch("sdf.minisdf", move |ev: Event| {
-                let start = Instant::now();
-                if !ev.kind.is_modify() {
-                    return;
-                }
+        Self {
+            recv,
+            dependency_recv,
+            base_recv,
+            watcher,
+            wake,
+            generation,
+            base_shaders,
+            requested_base: None,
+            #[cfg(feature = "hot-reload")]
+            base_builder: None,
+            last_error: None,
+            device,
+            history: ShaderHistory::new(history_length),
+            selected_module: None,
+        }
+    }
 
-                //First of all, try to run the compiler
-                let modules = match msdfc::compile_file("sdf.minisdf") {
-                    Ok(m) => m,
-                    Err(e) => {
-                        log::error!("Failed to compile sdf.minisdf: {e}");
-                        return;
-                    }
-                };
-
-                //now, try to inject all fields, based on the name using the patcher for our base module
-                let mut patch = patcher.patch();
-                for (name, module) in modules {
-                    println!("Injecting module {name}");
-
-                    //Locally patch the module's memory model
-                    let module = {
-                        let mmpatcher = match spv_patcher::Module::new(
-                            bytemuck::cast_slice(&module).to_vec(),
-                        ) {
-                            Err(e) => {
-                                log::error!("Could not load patch module {name} into patcher: {e}");
-                                continue;
-                            }
-                            Ok(k) => k,
-                        };
-
-                        match mmpatcher.patch().patch(spv_patcher::patch::MemoryModel {
-                            from: (
-                                patch_function::rspirv::spirv::AddressingModel::Logical,
-                                patch_function::rspirv::spirv::MemoryModel::GLSL450,
-                            ),
-                            to: (
-                                patch_function::rspirv::spirv::AddressingModel::Logical,
-                                patch_function::rspirv::spirv::MemoryModel::Vulkan,
-                            ),
-                        }) {
-                            Err(e) => {
-                                log::error!("Failed to mutate memory model for {name}: {e}");
-                                continue;
-                            }
-                            Ok(k) => k.assemble(),
-                        }
-                    };
-
-                    let static_patch =
-                        match StaticReplace::new_from_bytes(&bytemuck::cast_slice(&module), 0) {
-                            Err(e) => {
-                                log::error!("Failed to build patch for {name}: {e}");
-                                return;
-                            }
-                            Ok(p) => p,
-                        };
-
-                    patch = match patch.patch(static_patch) {
-                        Ok(p) => p,
-                        Err(e) => {
-                            log::error!("Failed to patch {name}: {e}");
-                            return;
-                        }
-                    }
+    ///Returns the newest shader module, or the module of a newly selected history entry, if there is any.
+    /// Failed patches are tracked in [Self::last_error].
+    pub fn fetch_new_module(&mut self) -> Option<PatchedModule> {
+        #[cfg(feature = "hot-reload")]
+        self.apply_rebuilt_base();
+
+        if let Some(files) = self.dependency_recv.try_iter().last() {
+            if let Err(e) = self.watcher.set_files(&files) {
+                log::error!("Could not watch dependencies {files:?}: {e}");
+            }
+        }
+
+        for (index, accepted) in self.base_recv.try_iter() {
+            if accepted {
+                self.base_shaders.select(index);
+                log::warn!(
+                    "Switched to base shader {}",
+                    self.base_shaders.get(index).name
+                );
+            } else {
+                log::error!(
+                    "Keeping base shader {}, {} could not be loaded",
+                    self.base_shaders.selected().name,
+                    self.base_shaders.get(index).name
+                );
+            }
+            if self.requested_base == Some(index) {
+                self.requested_base = None;
+            }
+        }
+
+        let mut newest = self.selected_module.take();
+        loop {
+            match self.recv.try_recv() {
+                Ok(Ok((new, entry))) => {
+                    self.last_error = None;
+                    newest = Some(PatchedModule::new(new, &entry.code));
+                    self.history.push(entry);
+                }
+                Ok(Err(e)) => self.last_error = Some(e),
+                Err(TryRecvError::Disconnected) => {
+                    log::error!("Patch receiver is disconnected, restart the application!");
+                    return newest;
                 }
+                Err(TryRecvError::Empty) => return newest,
+            }
+        }
+    }
 
-                let new_module_code = patch.assemble();
-                match ShaderModule::new_from_bytes(&device, bytemuck::cast_slice(&new_module_code))
-                {
-                    Ok(sm) => {
-                        println!(
-                            "Successfuly patched in {}ms",
-                            start.elapsed().as_secs_f32() * 1000.0
-                        );
-                        let _ = send.send(sm.into());
-                    }
-                    Err(e) => {
-                        log::error!("Could not build shader module for patched shader: {e}")
+    ///Selects the history entry `delta` steps newer (positive) or older (negative) than the current one.
+    /// The entry's module is returned by the next [Self::fetch_new_module] call.
+    pub fn step_history(&mut self, delta: isize) {
+        let code = match self.history.step(delta) {
+            Some(entry) => entry.code.clone(),
+            None => return,
+        };
+
+        match ShaderModule::new_from_bytes(&self.device, bytemuck::cast_slice(&code)) {
+            Ok(sm) => self.selected_module = Some(PatchedModule::new(OoS::new(sm), &code)),
+            Err(e) => log::error!("Could not build shader module for history entry: {e}"),
+        }
+    }
+
+    ///Writes the source of the selected history entry back to the watched file, and all files it included.
+    pub fn write_selected_source(&self) {
+        match self
+            .history
+            .selected()
+            .and_then(|entry| entry.source.as_ref())
+        {
+            Some(source) => {
+                for (file, content) in source.files.iter().zip(&source.contents) {
+                    match std::fs::write(file, content) {
+                        Ok(()) => log::warn!("Restored {file:?} from shader history"),
+                        Err(e) => log::error!("Could not write {file:?}: {e}"),
                     }
                 }
-            })
-            .expect("Could not schedule sdf-file watcher!");
+            }
+            None => log::warn!("Selected history entry has no source"),
+        }
+    }
 
-        Self {
-            recv,
-            hotwatch: watcher,
+    ///Switches to the next loaded base shader, and patches the sdf file into it. The selection only changes once
+    /// the worker accepted the new base shader.
+    pub fn next_base_shader(&mut self) {
+        let current = self
+            .requested_base
+            .unwrap_or_else(|| self.base_shaders.selected_index());
+        match self.base_shaders.next(current) {
+            Some(index) => {
+                log::warn!("Loading base shader {}", self.base_shaders.get(index).name);
+                self.request_base(index);
+            }
+            None => log::warn!("No other base shader loaded, see --base-shader"),
+        }
+    }
+
+    ///Sends the base shader at `index` to the worker.
+    fn request_base(&mut self, index: usize) {
+        self.requested_base = Some(index);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        let _ = self.wake.send(WorkerRequest::SetBase {
+            index,
+            code: self.base_shaders.get(index).code.clone(),
+        });
+    }
+
+    ///Stores the newest rebuild of the base shader crate, and patches the sdf file into it if its variant is
+    /// selected, or about to be. The result is returned by [Self::fetch_new_module] like any other patch.
+    #[cfg(feature = "hot-reload")]
+    fn apply_rebuilt_base(&mut self) {
+        let (variant, code) = match &self.base_builder {
+            Some(builder) => match builder.newest_build() {
+                Some(code) => (builder.variant(), code),
+                None => return,
+            },
+            None => return,
+        };
+
+        let name = BaseShaders::builtin_name(variant.name);
+        let current = self
+            .requested_base
+            .unwrap_or_else(|| self.base_shaders.selected_index());
+        match self.base_shaders.replace(&name, code) {
+            Ok(index) if index == current => self.request_base(index),
+            Ok(_) => log::warn!("Rebuilt base shader {name}, it is used once selected"),
+            Err(e) => log::error!("Ignoring rebuilt base shader {name}: {e}"),
+        }
+    }
+
+    ///Rebuilds `variant` of the base shader crate at `shader_crate` whenever it changes, and patches the sdf file
+    /// into the new base shader.
+    #[cfg(feature = "hot-reload")]
+    pub fn watch_base_shader(&mut self, shader_crate: &Path, variant: &str) {
+        let variant = match Variant::find(variant) {
+            Some(variant) => variant,
+            None => {
+                log::error!("No base shader variant {variant}, not watching {shader_crate:?}");
+                return;
+            }
+        };
+        match BaseShaderBuilder::new(shader_crate, variant) {
+            Ok(builder) => self.base_builder = Some(builder),
+            Err(e) => log::error!("Could not watch base shader crate {shader_crate:?}: {e}"),
         }
     }
 
-    pub fn fetch_new_module(&mut self) -> Option<OoS<ShaderModule>> {
-        match self.recv.try_recv() {
-            Ok(new) => Some(new),
-            Err(TryRecvError::Disconnected) => {
-                log::error!("Patch receiver is disconnected, restart the application!");
-                None
+    ///The error of the most recent patch attempt, if it failed.
+    pub fn last_error(&self) -> Option<&PatchError> {
+        self.last_error.as_ref()
+    }
+}
+
+///Work for the compile worker.
+enum WorkerRequest {
+    ///Patch the sdf file.
+    Patch,
+    ///Replace the base shader with the one at `index` of [BaseShaders], then patch the sdf file into it.
+    SetBase { index: usize, code: Vec<u8> },
+}
+
+///Patches the sdf file whenever woken up, and reports changed includes. Requests that queue up while patching are merged, and results
+/// are dropped if the file changed while they were being patched.
+fn compile_worker(
+    mut pipeline: SdfPatchPipeline,
+    device: Arc<marpii::context::Device>,
+    sdf_file: PathBuf,
+    generation: Arc<AtomicU64>,
+    wake: Receiver<WorkerRequest>,
+    send: Sender<Result<(OoS<ShaderModule>, HistoryEntry), PatchError>>,
+    dependency_send: Sender<Vec<PathBuf>>,
+    base_send: Sender<(usize, bool)>,
+) {
+    let mut dependencies = vec![sdf_file.clone()];
+    while let Ok(request) = wake.recv() {
+        let requests = std::iter::once(request)
+            .chain(wake.try_iter())
+            .collect::<Vec<_>>();
+        let patch_requested = requests
+            .iter()
+            .any(|request| matches!(request, WorkerRequest::Patch));
+        let new_base = requests
+            .into_iter()
+            .filter_map(|request| match request {
+                WorkerRequest::Patch => None,
+                WorkerRequest::SetBase { index, code } => Some((index, code)),
+            })
+            .last();
+        if let Some((index, code)) = new_base {
+            let result = pipeline.set_base_shader(&code);
+            let _ = base_send.send((index, result.is_ok()));
+            if let Err(e) = result {
+                log::error!("{e}");
+                let _ = send.send(Err(e));
+                //NOTE: Source changes merged into this batch are still patched, into the previous base.
+                if !patch_requested {
+                    continue;
+                }
+            }
+        }
+
+        let snapshot = generation.load(Ordering::Acquire);
+        let is_outdated = || generation.load(Ordering::Acquire) != snapshot;
+
+        //NOTE: Update the watched files even if patching fails later, so fixing an included file is noticed.
+        if let Ok(source) = resolve_includes(&sdf_file) {
+            if source.files != dependencies {
+                dependencies = source.files;
+                let _ = dependency_send.send(dependencies.clone());
+            }
+        }
+
+        let result = pipeline
+            .patch_file_with(&sdf_file, &is_outdated)
+            .and_then(|mut patched| {
+                let start = Instant::now();
+                let module =
+                    ShaderModule::new_from_bytes(&device, bytemuck::cast_slice(&patched.code))
+                        .map_err(|e| PatchError::ModuleCreation(e.to_string()))?;
+                patched.timings.module_creation = start.elapsed();
+                Ok((module, patched))
+            });
+
+        match result {
+            Err(PatchError::Outdated) => log::info!("Discarded outdated patch"),
+            Ok(_) if is_outdated() => log::info!("Discarded outdated patch"),
+            Ok((sm, patched)) => {
+                println!("Successfuly patched in {}", patched.timings);
+                let _ = send.send(Ok((
+                    sm.into(),
+                    HistoryEntry {
+                        code: patched.code,
+                        source: Some(patched.source),
+                    },
+                )));
+            }
+            Err(e) => {
+                log::error!("{e}");
+                let _ = send.send(Err(e));
             }
-            _ => None,
         }
     }
 }
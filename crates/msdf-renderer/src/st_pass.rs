@@ -4,32 +4,61 @@ use marpii::{
     OoS,
 };
 use marpii_rmg::{CtxRmg, ImageHandle, Rmg, Task};
+use marpii_rmg_shared::ResourceHandle;
 
-use crate::{patcher::Patcher, Camera};
-use shared::{glam::Vec3, RenderUniform};
-use std::sync::Arc;
+use crate::{
+    base_shaders::BaseShaders,
+    patcher::{PatchedModule, Patcher},
+    Camera,
+};
+use sdf_patcher::{PatchError, SdfPatchPipeline, CONE_PREPASS_ENTRY_POINT, ENTRY_POINT};
+use shared::{flags, glam::Vec3, render_mode, RenderUniform};
+use std::{path::Path, sync::Arc};
+use winit::event::{ElementState, Event, VirtualKeyCode, WindowEvent};
 
 pub struct SphereTracing {
     patcher: Patcher,
     pipeline_layout: Arc<PipelineLayout>,
     pipeline: Arc<ComputePipeline>,
+    ///Pipeline of the cone pre-pass, built from the same module. `None` if the module has no pre-pass.
+    prepass_pipeline: Option<Arc<ComputePipeline>>,
     pub target_image: ImageHandle,
+    ///Start distances written by the cone pre-pass, if it is used.
+    start_image: Option<ImageHandle>,
     pc: PushConstant<shared::RenderUniform>,
 }
 
 impl SphereTracing {
-    pub fn new(rmg: &mut Rmg, base_resolution: Extent2D) -> Self {
+    pub fn new(
+        rmg: &mut Rmg,
+        base_resolution: Extent2D,
+        sdf_file: &Path,
+        pipeline: SdfPatchPipeline,
+        history_length: usize,
+        base_shaders: BaseShaders,
+    ) -> Self {
         println!("Create for resolution: {base_resolution:?}");
-        let mut patcher = Patcher::new(rmg.ctx.device.clone());
-        let base_shader = patcher
+        let mut patcher = Patcher::new(
+            rmg.ctx.device.clone(),
+            sdf_file,
+            pipeline,
+            history_length,
+            base_shaders,
+        );
+        let mut base_shader = patcher
             .fetch_new_module()
             .expect("Could not get base shader!");
 
         let pc = PushConstant::new(RenderUniform::default(), vk::ShaderStageFlags::COMPUTE);
+        let prepass_pipeline = create_prepass_pipeline(
+            &rmg.ctx.device,
+            &rmg.resources.bindless_layout(),
+            &mut base_shader,
+        );
         let shader_stage = ShaderStage::from_module(
-            base_shader,
+            base_shader.module,
             vk::ShaderStageFlags::COMPUTE,
-            "renderer".to_owned(),
+            ENTRY_POINT.to_owned(),
         );
         let pipeline = Arc::new(
             ComputePipeline::new(
@@ -58,22 +87,31 @@ impl SphereTracing {
             pc,
             pipeline_layout: rmg.resources.bindless_layout(),
             pipeline,
+            prepass_pipeline,
+            start_image: None,
         }
     }
 
+    ///Switches to the newest patched module, if there is one. Called once per frame before recording.
     pub fn try_new_pipeline(&mut self, ctx: &CtxRmg) {
-        let nm = if let Some(new_module) = self.patcher.fetch_new_module() {
+        let mut nm = if let Some(new_module) = self.patcher.fetch_new_module() {
             log::info!("New Shader module!");
             new_module
         } else {
             return;
         };
 
-        let ss = ShaderStage::from_module(nm, vk::ShaderStageFlags::COMPUTE, "renderer".to_owned());
+        let prepass_pipeline = create_prepass_pipeline(&ctx.device, &self.pipeline_layout, &mut nm);
+        let ss = ShaderStage::from_module(
+            nm.module,
+            vk::ShaderStageFlags::COMPUTE,
+            ENTRY_POINT.to_owned(),
+        );
 
         match ComputePipeline::new(&ctx.device, &ss, None, self.pipeline_layout.clone()) {
             Ok(np) => {
                 self.pipeline = Arc::new(np);
+                self.prepass_pipeline = prepass_pipeline;
             }
             Err(e) => {
                 log::error!("Failed to build new pipeline: {e}");
@@ -82,10 +120,203 @@ impl SphereTracing {
         }
     }
 
+    ///Handles the shader history, base shader, render mode, tracing and shadow hotkeys.
+    pub fn on_event(&mut self, event: &Event<()>) {
+        match event {
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput { input, .. },
+                ..
+            } => match (input.virtual_keycode, input.state) {
+                (Some(VirtualKeyCode::LBracket), ElementState::Released) => {
+                    self.patcher.step_history(-1)
+                }
+                (Some(VirtualKeyCode::RBracket), ElementState::Released) => {
+                    self.patcher.step_history(1)
+                }
+                (Some(VirtualKeyCode::P), ElementState::Released) => {
+                    self.patcher.write_selected_source()
+                }
+                (Some(VirtualKeyCode::B), ElementState::Released) => {
+                    self.patcher.next_base_shader()
+                }
+                (Some(VirtualKeyCode::M), ElementState::Released) => self.step_render_mode(1),
+                (Some(VirtualKeyCode::N), ElementState::Released) => {
+                    self.step_render_mode(render_mode::COUNT - 1)
+                }
+                (Some(key @ (VirtualKeyCode::F1 | VirtualKeyCode::F2)), ElementState::Released) => {
+                    let epsilon = self.pc.get_content().epsilon;
+                    self.epsilon(if key == VirtualKeyCode::F1 {
+                        epsilon / 2.0
+                    } else {
+                        epsilon * 2.0
+                    });
+                    log::warn!("Epsilon: {}", self.pc.get_content().epsilon);
+                }
+                (Some(key @ (VirtualKeyCode::F3 | VirtualKeyCode::F4)), ElementState::Released) => {
+                    let max_steps = self.pc.get_content().max_steps;
+                    self.max_steps(if key == VirtualKeyCode::F3 {
+                        (max_steps / 2).max(1)
+                    } else {
+                        max_steps.saturating_mul(2)
+                    });
+                    log::warn!("Max steps: {}", self.pc.get_content().max_steps);
+                }
+                (Some(key @ (VirtualKeyCode::F5 | VirtualKeyCode::F6)), ElementState::Released) => {
+                    let max_t = self.pc.get_content().max_t;
+                    self.max_distance(if key == VirtualKeyCode::F5 {
+                        max_t / 2.0
+                    } else {
+                        max_t * 2.0
+                    });
+                    log::warn!("Max distance: {}", self.pc.get_content().max_t);
+                }
+                (Some(VirtualKeyCode::F7), ElementState::Released) => {
+                    let enabled = self.pc.get_content().flags & flags::PIXEL_EPSILON == 0;
+                    self.pixel_epsilon(enabled);
+                    log::warn!("Pixel footprint epsilon: {enabled}");
+                }
+                (Some(VirtualKeyCode::F8), ElementState::Released) => {
+                    let content = self.pc.get_content();
+                    let enabled = content.flags & flags::RELAXED == 0;
+                    self.relaxed(enabled, content.relaxation);
+                    log::warn!("Tracing mode: {}", self.trace_mode());
+                }
+                (Some(VirtualKeyCode::F10), ElementState::Released) => {
+                    let content = self.pc.get_content();
+                    let enabled = content.flags & flags::SOFT_SHADOWS == 0;
+                    self.soft_shadows(enabled, content.shadow_softness);
+                    log::warn!("Soft shadows: {enabled}");
+                }
+                (
+                    Some(key @ (VirtualKeyCode::F11 | VirtualKeyCode::F12)),
+                    ElementState::Released,
+                ) => {
+                    let content = self.pc.get_content();
+                    let softness = if key == VirtualKeyCode::F11 {
+                        content.shadow_softness / 2.0
+                    } else {
+                        content.shadow_softness * 2.0
+                    };
+                    self.soft_shadows(content.flags & flags::SOFT_SHADOWS != 0, softness);
+                    log::warn!("Shadow softness: {softness}");
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    ///Rebuilds `variant` of the base shader and re-patches it whenever the crate at `shader_crate` changes.
+    #[cfg(feature = "hot-reload")]
+    pub fn watch_base_shader(&mut self, shader_crate: &Path, variant: &str) {
+        self.patcher.watch_base_shader(shader_crate, variant)
+    }
+
+    ///The error of the most recent, failed patch, if the current pipeline is outdated.
+    pub fn patch_error(&self) -> Option<&PatchError> {
+        self.patcher.last_error()
+    }
+
     pub fn offset_parameter(&mut self, new: Vec3) {
         self.pc.get_content_mut().offset = new.into();
     }
 
+    pub fn fov(&mut self, fov: f32) {
+        self.pc.get_content_mut().fov = fov;
+    }
+
+    ///Sets one of the [render_mode] constants.
+    pub fn render_mode(&mut self, mode: u32) {
+        self.pc.get_content_mut().render_mode = mode;
+    }
+
+    ///Distance to the surface at which a ray counts as hit.
+    pub fn epsilon(&mut self, epsilon: f32) {
+        self.pc.get_content_mut().epsilon = epsilon;
+    }
+
+    pub fn max_steps(&mut self, max_steps: u32) {
+        self.pc.get_content_mut().max_steps = max_steps;
+    }
+
+    ///Distance after which a ray counts as miss.
+    pub fn max_distance(&mut self, max_t: f32) {
+        self.pc.get_content_mut().max_t = max_t;
+    }
+
+    ///Grows the epsilon with the pixel footprint, see [RenderUniform::surface_epsilon].
+    pub fn pixel_epsilon(&mut self, enabled: bool) {
+        let content = self.pc.get_content_mut();
+        if enabled {
+            content.flags |= flags::PIXEL_EPSILON;
+        } else {
+            content.flags &= !flags::PIXEL_EPSILON;
+        }
+    }
+
+    ///Enables over-relaxed sphere tracing with the step scale `relaxation`.
+    pub fn relaxed(&mut self, enabled: bool, relaxation: f32) {
+        let content = self.pc.get_content_mut();
+        content.relaxation = relaxation;
+        if enabled {
+            content.flags |= flags::RELAXED;
+        } else {
+            content.flags &= !flags::RELAXED;
+        }
+    }
+
+    ///Enables soft shadows with a penumbra of size `softness`.
+    pub fn soft_shadows(&mut self, enabled: bool, softness: f32) {
+        let content = self.pc.get_content_mut();
+        content.shadow_softness = softness;
+        if enabled {
+            content.flags |= flags::SOFT_SHADOWS;
+        } else {
+            content.flags &= !flags::SOFT_SHADOWS;
+        }
+    }
+
+    ///Describes the sphere tracing variant in use.
+    pub fn trace_mode(&self) -> String {
+        let content = self.pc.get_content();
+        let mut mode = if content.flags & flags::RELAXED != 0 {
+            format!("relaxed, ω = {}", content.relaxation)
+        } else {
+            "plain".to_owned()
+        };
+        if content.flags & flags::CONE_PREPASS != 0 {
+            mode.push_str(", cone pre-pass");
+        }
+        mode
+    }
+
+    ///Starts the rays at the distances in `start_image`, written by the cone pre-pass. `None` starts them at the
+    /// camera.
+    pub fn start_image(&mut self, start_image: Option<ImageHandle>) {
+        let content = self.pc.get_content_mut();
+        if start_image.is_some() {
+            content.flags |= flags::CONE_PREPASS;
+        } else {
+            content.flags &= !flags::CONE_PREPASS;
+        }
+        self.start_image = start_image;
+    }
+
+    ///Pipeline of the cone pre-pass for the current shader, if it has one.
+    pub fn prepass_pipeline(&self) -> Option<Arc<ComputePipeline>> {
+        self.prepass_pipeline.clone()
+    }
+
+    pub fn uniform(&self) -> &RenderUniform {
+        self.pc.get_content()
+    }
+
+    fn step_render_mode(&mut self, step: u32) {
+        let mode = (self.pc.get_content().render_mode + step) % render_mode::COUNT;
+        self.render_mode(mode);
+        log::warn!("Render mode: {}", render_mode::name(mode));
+    }
+
     pub fn notify_resolution(&mut self, rmg: &mut Rmg, resolution: Extent2D) {
         if self.target_image.extent_2d() == resolution {
             return;
@@ -132,6 +363,16 @@ impl Task for SphereTracing {
                 vk::ImageLayout::GENERAL,
             )
             .unwrap();
+        if let Some(start_image) = &self.start_image {
+            registry
+                .request_image(
+                    start_image,
+                    vk::PipelineStageFlags2::COMPUTE_SHADER,
+                    vk::AccessFlags2::SHADER_STORAGE_READ,
+                    vk::ImageLayout::GENERAL,
+                )
+                .unwrap();
+        }
 
         registry.register_asset(self.pipeline.clone());
     }
@@ -139,10 +380,8 @@ impl Task for SphereTracing {
     fn pre_record(
         &mut self,
         resources: &mut marpii_rmg::Resources,
-        ctx: &marpii_rmg::CtxRmg,
+        _ctx: &marpii_rmg::CtxRmg,
     ) -> Result<(), marpii_rmg::RecordError> {
-        self.try_new_pipeline(ctx);
-
         self.pc.get_content_mut().resolution = [
             self.target_image.extent_2d().width,
             self.target_image.extent_2d().height,
@@ -150,6 +389,12 @@ impl Task for SphereTracing {
         self.pc.get_content_mut().target_image = resources
             .resource_handle_or_bind(self.target_image.clone())
             .unwrap();
+        self.pc.get_content_mut().start_image = match &self.start_image {
+            Some(start_image) => resources
+                .resource_handle_or_bind(start_image.clone())
+                .unwrap(),
+            None => ResourceHandle::INVALID,
+        };
         Ok(())
     }
 
@@ -178,3 +423,27 @@ impl Task for SphereTracing {
         }
     }
 }
+
+///Creates the cone pre-pass pipeline, if `module` has its entry point.
+fn create_prepass_pipeline(
+    device: &Arc<marpii::context::Device>,
+    layout: &Arc<PipelineLayout>,
+    module: &mut PatchedModule,
+) -> Option<Arc<ComputePipeline>> {
+    if !module.has_cone_prepass {
+        return None;
+    }
+
+    let stage = ShaderStage::from_module(
+        module.module.share(),
+        vk::ShaderStageFlags::COMPUTE,
+        CONE_PREPASS_ENTRY_POINT.to_owned(),
+    );
+    match ComputePipeline::new(device, &stage, None, layout.clone()) {
+        Ok(pipeline) => Some(Arc::new(pipeline)),
+        Err(e) => {
+            log::error!("Failed to build cone pre-pass pipeline: {e}");
+            None
+        }
+    }
+}
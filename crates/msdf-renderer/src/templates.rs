@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+
+///The example field from the README.
+const STARTER: &str = r#"field myfield(offset: vec3){
+    union(){
+        repeat(3.0, 10.0, 4.0){
+            sub(){
+                box(vec3(1.0, 2.0, 1.0))
+            }{
+                repeat(0.75, 0.75, 0.75){
+                    sphere(0.4)
+                }
+            }
+        }
+    }{
+        translate(offset){
+            sphere(1.75)
+        }
+    }
+}
+"#;
+
+///Built-in `.minisdf` templates that are written if the watched file does not exist yet.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Template {
+    ///Repeated, hollowed boxes and a sphere controlled by `offset`.
+    #[default]
+    Starter,
+    ///Large, repeated planes and beams.
+    Planes,
+    ///Repeated boxes with a carved sphere.
+    Fractal,
+}
+
+impl Template {
+    pub fn source(&self) -> &'static str {
+        match self {
+            Template::Starter => STARTER,
+            Template::Planes => include_str!("../../../planes.minisdf"),
+            Template::Fractal => include_str!("../../../fractal.minisdf"),
+        }
+    }
+
+    ///Writes the template to `path`, if no file exists there yet. Returns true if the file was created.
+    pub fn write_if_missing(&self, path: &Path) -> std::io::Result<bool> {
+        if path.exists() {
+            return Ok(false);
+        }
+
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        std::fs::write(path, self.source())?;
+        Ok(true)
+    }
+}
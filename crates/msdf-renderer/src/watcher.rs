@@ -0,0 +1,198 @@
+use ahash::{AHashMap, AHashSet};
+use hotwatch::notify::{self, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{mpsc::RecvTimeoutError, Arc, Mutex},
+    time::Duration,
+};
+
+///Content hash of every watched file. `None` if the file is currently missing.
+type WatchedFiles = AHashMap<PathBuf, Option<u64>>;
+
+///Watches a set of files, including saves that replace a file by renaming a temporary file over it,
+/// deleting and re-creating it.
+///
+/// Since those saves replace the watched inode, the files' parent directories are watched instead of the files
+/// themselves. They are watched non-recursively, so build artifacts next to the files don't cause work. Bursts of
+/// events are merged by only reporting a change if a file's content actually changed.
+pub struct FileWatcher {
+    watcher: RecommendedWatcher,
+    files: Arc<Mutex<WatchedFiles>>,
+    watched_dirs: AHashSet<PathBuf>,
+}
+
+fn content_hash(path: &Path) -> Option<u64> {
+    let content = std::fs::read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+impl FileWatcher {
+    ///Calls `on_change` whenever the content of `file` changes. Events are collected until there was none for
+    /// `delay`, so a save is only reported once.
+    pub fn new(
+        file: &Path,
+        delay: Duration,
+        mut on_change: impl FnMut() + Send + 'static,
+    ) -> Result<Self, notify::Error> {
+        let (send, recv) = std::sync::mpsc::channel();
+        let files = Arc::new(Mutex::new(WatchedFiles::default()));
+
+        {
+            let files = files.clone();
+            std::thread::Builder::new()
+                .name("sdf-file-watcher".to_owned())
+                .spawn(move || {
+                    //NOTE: Ends once the watcher, and with it the sender, is dropped.
+                    while let Ok(ev) = recv.recv() {
+                        let mut changed = Self::handle(ev, &files);
+                        loop {
+                            match recv.recv_timeout(delay) {
+                                Ok(ev) => changed |= Self::handle(ev, &files),
+                                Err(RecvTimeoutError::Timeout) => break,
+                                Err(RecvTimeoutError::Disconnected) => return,
+                            }
+                        }
+                        if changed {
+                            on_change();
+                        }
+                    }
+                })?;
+        }
+
+        let mut watcher = FileWatcher {
+            watcher: notify::recommended_watcher(send)?,
+            files,
+            watched_dirs: AHashSet::default(),
+        };
+        watcher.set_files(&[file.to_path_buf()])?;
+        Ok(watcher)
+    }
+
+    ///Replaces the set of watched files, for instance when the includes of the main file changed.
+    pub fn set_files(&mut self, files: &[PathBuf]) -> Result<(), notify::Error> {
+        let files = files
+            .iter()
+            .map(|f| f.canonicalize())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let dirs = files
+            .iter()
+            .map(|file| {
+                file.parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|| PathBuf::from("."))
+            })
+            .collect::<AHashSet<_>>();
+        for dir in dirs.difference(&self.watched_dirs) {
+            self.watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+        for dir in self.watched_dirs.difference(&dirs) {
+            if let Err(e) = self.watcher.unwatch(dir) {
+                log::warn!("Could not stop watching {dir:?}: {e}");
+            }
+        }
+        self.watched_dirs = dirs;
+
+        let mut watched = self.files.lock().unwrap();
+        //Keep the hashes of files that are still watched, so unchanged files do not trigger a change.
+        let mut new_set = WatchedFiles::default();
+        for file in files {
+            let hash = watched.remove(&file).unwrap_or_else(|| content_hash(&file));
+            new_set.insert(file, hash);
+        }
+        *watched = new_set;
+
+        Ok(())
+    }
+
+    fn handle(ev: notify::Result<Event>, watched: &Mutex<WatchedFiles>) -> bool {
+        match ev {
+            Ok(ev) => Self::on_event(&ev, &mut watched.lock().unwrap()),
+            Err(e) => {
+                log::error!("File watcher error: {e}");
+                false
+            }
+        }
+    }
+
+    ///Updates the hashes of the watched files `ev` touches. Returns true if any of their contents changed.
+    fn on_event(ev: &Event, watched: &mut WatchedFiles) -> bool {
+        match ev.kind {
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {}
+            _ => return false,
+        }
+
+        let mut changed = false;
+        for path in &ev.paths {
+            let last_hash = match watched.get_mut(path) {
+                Some(hash) => hash,
+                None => continue,
+            };
+
+            //NOTE: For rename based saves the file might already be back, even though we got a
+            //      remove event. So always check the file itself.
+            let hash = content_hash(path);
+            if hash.is_none() {
+                if last_hash.is_some() {
+                    log::warn!("{path:?} was removed, waiting for it to be re-created");
+                }
+            } else if hash != *last_hash {
+                changed = true;
+            }
+            *last_hash = hash;
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hotwatch::notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+
+    #[test]
+    fn tracks_rename_delete_and_recreate() {
+        let dir = std::env::temp_dir().join(format!("msdf-watcher-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("sdf.minisdf");
+        let other = dir.join("other.minisdf");
+        std::fs::write(&file, "a").unwrap();
+
+        let mut watched = WatchedFiles::default();
+        watched.insert(file.clone(), content_hash(&file));
+        let event = |kind, path: &Path| Event::new(kind).add_path(path.to_path_buf());
+
+        //Touching the file without changing it, or changing unwatched files is no change.
+        let modify = EventKind::Modify(ModifyKind::Any);
+        assert!(!FileWatcher::on_event(&event(modify, &file), &mut watched));
+        std::fs::write(&other, "b").unwrap();
+        assert!(!FileWatcher::on_event(&event(modify, &other), &mut watched));
+
+        //Rename based save.
+        std::fs::write(&other, "b").unwrap();
+        std::fs::rename(&other, &file).unwrap();
+        let rename = EventKind::Modify(ModifyKind::Name(RenameMode::To));
+        assert!(FileWatcher::on_event(&event(rename, &file), &mut watched));
+
+        //Deleting only reports once the file is back.
+        std::fs::remove_file(&file).unwrap();
+        let remove = EventKind::Remove(RemoveKind::File);
+        assert!(!FileWatcher::on_event(&event(remove, &file), &mut watched));
+        assert_eq!(watched[&file], None);
+        std::fs::write(&file, "b").unwrap();
+        let create = EventKind::Create(CreateKind::File);
+        assert!(FileWatcher::on_event(&event(create, &file), &mut watched));
+        assert!(!FileWatcher::on_event(&event(modify, &file), &mut watched));
+
+        //Access events are ignored.
+        std::fs::write(&file, "c").unwrap();
+        let access = EventKind::Access(hotwatch::notify::event::AccessKind::Any);
+        assert!(!FileWatcher::on_event(&event(access, &file), &mut watched));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
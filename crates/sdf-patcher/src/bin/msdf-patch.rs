@@ -0,0 +1,96 @@
+//! Headless patcher. Compiles a `.minisdf` file, patches it into the base shader and writes the
+//! result to disk, without needing a GPU.
+
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use patch_function::rspirv::{self, binary::Disassemble};
+use sdf_patcher::{MemoryModelFixup, PatchRouting, Route, SdfPatchPipeline, BASE_SHADER};
+
+///Patches the fields of a .minisdf file into the renderer's base shader.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    ///The .minisdf file to compile.
+    input: PathBuf,
+
+    ///Output SPIR-V file. Defaults to the input file with a `.spv` extension.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    ///Routes a field to a patch point of the base shader, formatted as `field=patch_point`. Can be used multiple times.
+    #[arg(long = "route")]
+    routes: Vec<Route>,
+
+    ///Directory the intermediate per-field modules are written to.
+    #[arg(long)]
+    dump_fields: Option<PathBuf>,
+
+    ///Also writes a text disassembly next to every written module.
+    #[arg(long)]
+    disassemble: bool,
+}
+
+fn write_module(path: &Path, code: &[u32], disassemble: bool) -> Result<(), String> {
+    std::fs::write(path, bytemuck::cast_slice(code))
+        .map_err(|e| format!("Could not write {path:?}: {e}"))?;
+    println!("Wrote {path:?}");
+
+    if disassemble {
+        let module = rspirv::dr::load_words(code)
+            .map_err(|e| format!("Could not load {path:?} for disassembly: {e}"))?;
+        let asm_path = path.with_extension("spvasm");
+        std::fs::write(&asm_path, module.disassemble())
+            .map_err(|e| format!("Could not write {asm_path:?}: {e}"))?;
+        println!("Wrote {asm_path:?}");
+    }
+
+    Ok(())
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let pipeline = SdfPatchPipeline::new(BASE_SHADER)
+        .map_err(|e| e.to_string())?
+        .with_routing(PatchRouting::new(args.routes.clone()));
+    if let Some(dir) = &args.dump_fields {
+        let compiled = SdfPatchPipeline::compile(&args.input).map_err(|e| e.to_string())?;
+        std::fs::create_dir_all(dir).map_err(|e| format!("Could not create {dir:?}: {e}"))?;
+        for (name, module) in &compiled.fields {
+            write_module(
+                &dir.join(format!("{name}.msdfc.spv")),
+                module,
+                args.disassemble,
+            )?;
+            let fixed = MemoryModelFixup::apply(module)
+                .map_err(|e| format!("Failed to mutate memory model for field {name}: {e}"))?;
+            write_module(
+                &dir.join(format!("{name}.patch.spv")),
+                &fixed,
+                args.disassemble,
+            )?;
+        }
+    }
+
+    let patched = pipeline
+        .patch_file(&args.input)
+        .map_err(|e| e.to_string())?;
+    println!("Patched in {}", patched.timings);
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.input.with_extension("spv"));
+    write_module(&output, &patched.code, args.disassemble)
+}
+
+fn main() {
+    let args = Args::parse();
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Warn)
+        .init()
+        .unwrap();
+
+    if let Err(e) = run(&args) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,150 @@
+//! Content addressed on-disk cache of patched modules.
+
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::words_from_bytes;
+
+///Version of the patch pipeline. Bump whenever the same inputs would produce different code, so
+/// old cache entries are not used anymore.
+pub const PIPELINE_VERSION: u32 = 2;
+
+///Key of a cached module. Hash of the minisdf source, the base shader, the pipeline configuration (routing and
+/// stages) and [PIPELINE_VERSION].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    pub fn new(source: &str, base_shader: &[u8], config: &str) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(PIPELINE_VERSION.to_le_bytes());
+        //NOTE: Length-prefix all parts, so moving bytes between them changes the key.
+        for part in [base_shader, config.as_bytes(), source.as_bytes()] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+
+        let hash = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        CacheKey(hash)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+///Directory of patched modules, named by their [CacheKey].
+#[derive(Debug, Clone)]
+pub struct PatchCache {
+    dir: PathBuf,
+}
+
+impl PatchCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        PatchCache { dir: dir.into() }
+    }
+
+    ///`$XDG_CACHE_HOME/msdf-renderer`, `$HOME/.cache/msdf-renderer` or the temp directory, in that order.
+    pub fn default_dir() -> PathBuf {
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        base.join("msdf-renderer")
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(format!("{}.spv", key.as_str()))
+    }
+
+    ///Loads the module stored for `key`, if there is any.
+    pub fn load(&self, key: &CacheKey) -> Option<Vec<u32>> {
+        let bytes = std::fs::read(self.entry(key)).ok()?;
+        let words = words_from_bytes(&bytes);
+        if words.is_none() {
+            log::warn!("Ignoring corrupted cache entry {}", key.as_str());
+        }
+        words
+    }
+
+    ///Stores `code` for `key`. Failing to do so is not fatal, so errors are only logged.
+    pub fn store(&self, key: &CacheKey, code: &[u32]) {
+        if let Err(e) = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(self.entry(key), bytemuck::cast_slice(code)))
+        {
+            log::warn!("Could not write cache entry {}: {e}", key.as_str());
+        }
+    }
+
+    ///Removes all cached modules.
+    pub fn clear(&self) -> std::io::Result<()> {
+        if !self.dir.exists() {
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == "spv").unwrap_or(false) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_depends_on_all_inputs() {
+        let key = CacheKey::new("sphere(1.0)", &[1, 2, 3, 4], "eval_sdf");
+        assert_eq!(key, CacheKey::new("sphere(1.0)", &[1, 2, 3, 4], "eval_sdf"));
+        assert_eq!(key.as_str().len(), 64);
+
+        assert_ne!(key, CacheKey::new("sphere(2.0)", &[1, 2, 3, 4], "eval_sdf"));
+        assert_ne!(key, CacheKey::new("sphere(1.0)", &[1, 2, 3, 5], "eval_sdf"));
+        assert_ne!(key, CacheKey::new("sphere(1.0)", &[1, 2, 3, 4], "other"));
+        //Moving bytes between the parts is a different key as well.
+        assert_ne!(CacheKey::new("ab", &[], "c"), CacheKey::new("b", &[], "ca"));
+    }
+
+    #[test]
+    fn stores_loads_and_clears_entries() {
+        let dir = std::env::temp_dir().join(format!("msdf-cache-{}", std::process::id()));
+        let cache = PatchCache::new(&dir);
+        let key = CacheKey::new("sphere(1.0)", &[], "");
+        let other = CacheKey::new("sphere(2.0)", &[], "");
+
+        //Loading from a directory that does not exist yet is just a miss.
+        assert_eq!(cache.load(&key), None);
+
+        cache.store(&key, &[0x07230203, 1, 2]);
+        assert_eq!(cache.load(&key), Some(vec![0x07230203, 1, 2]));
+        assert_eq!(cache.load(&other), None);
+
+        //Entries that are no whole number of words are ignored.
+        std::fs::write(cache.entry(&other), [1, 2, 3]).unwrap();
+        assert_eq!(cache.load(&other), None);
+
+        //Clearing only removes modules.
+        let unrelated = dir.join("notes.txt");
+        std::fs::write(&unrelated, "keep").unwrap();
+        cache.clear().unwrap();
+        assert_eq!(cache.load(&key), None);
+        assert!(!cache.entry(&other).exists());
+        assert!(unrelated.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(cache.clear().is_ok());
+    }
+}
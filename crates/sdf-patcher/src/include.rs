@@ -0,0 +1,276 @@
+//! Resolves `include "other.minisdf"` statements, so scenes can be split into several files.
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, Error)]
+#[error("{file:?} line {line}: {message}")]
+pub struct IncludeError {
+    pub file: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+///A `.minisdf` file with all includes expanded in place.
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedSource {
+    ///The combined program.
+    pub text: String,
+    ///The main file, followed by all files it includes (directly or indirectly).
+    pub files: Vec<PathBuf>,
+    ///Unmodified content of each file in `files`.
+    pub contents: Vec<String>,
+    ///For every line of `text`, the index into `files` and the 1-based line within that file.
+    pub lines: Vec<(usize, usize)>,
+}
+
+impl ResolvedSource {
+    ///Maps a 1-based line of the combined source back to its file and line.
+    pub fn origin(&self, line: usize) -> Option<(&Path, usize)> {
+        let (file, line) = self.lines.get(line.checked_sub(1)?)?;
+        Some((self.files.get(*file)?.as_path(), *line))
+    }
+
+    ///Rewrites every `line <n>` in `message`, which refers to a line of the combined source, to the file and line
+    /// it originates from. Other positions are left unchanged.
+    pub fn map_lines(&self, message: &str) -> String {
+        const LINE: &str = "line ";
+        let mut mapped = String::with_capacity(message.len());
+        let mut rest = message;
+        while let Some(pos) = rest.find(LINE) {
+            let (before, after) = rest.split_at(pos + LINE.len());
+            let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            match after[..digits]
+                .parse()
+                .ok()
+                .and_then(|line| self.origin(line))
+            {
+                Some((file, line)) => {
+                    let _ = write!(mapped, "{}{file:?} line {line}", &before[..pos]);
+                }
+                None => {
+                    mapped.push_str(before);
+                    mapped.push_str(&after[..digits]);
+                }
+            }
+            rest = &after[digits..];
+        }
+        mapped.push_str(rest);
+        mapped
+    }
+}
+
+///Parses `include "path"` (an optional `;` and a trailing `//` comment are allowed). Returns `None` if the line is
+/// no include statement.
+fn parse_include(line: &str) -> Option<Result<&str, String>> {
+    let rest = line.trim().strip_prefix("include")?;
+    if !rest.starts_with(char::is_whitespace) && !rest.starts_with('"') {
+        return None;
+    }
+
+    let path = rest
+        .trim_start()
+        .strip_prefix('"')
+        .and_then(|quoted| quoted.split_once('"'))
+        .filter(|(path, _)| !path.is_empty())
+        .and_then(|(path, tail)| {
+            let tail = tail.trim_start();
+            let tail = tail.strip_prefix(';').unwrap_or(tail).trim_start();
+            (tail.is_empty() || tail.starts_with("//")).then_some(path)
+        });
+    match path {
+        Some(path) => Some(Ok(path)),
+        None => Some(Err(format!(
+            "expected `include \"file.minisdf\"`, got `{}`",
+            line.trim()
+        ))),
+    }
+}
+
+///Reads `file` and recursively expands all includes. Paths are relative to the including file. Every file is
+/// included at most once, include cycles are reported as errors.
+pub fn resolve_includes(file: &Path) -> Result<ResolvedSource, IncludeError> {
+    let mut resolved = ResolvedSource::default();
+    let mut stack = Vec::new();
+    resolve_into(file, None, &mut resolved, &mut stack)?;
+    Ok(resolved)
+}
+
+fn resolve_into(
+    file: &Path,
+    included_from: Option<(&Path, usize)>,
+    resolved: &mut ResolvedSource,
+    stack: &mut Vec<PathBuf>,
+) -> Result<(), IncludeError> {
+    let error_at = |message: String| match included_from {
+        Some((from, line)) => IncludeError {
+            file: from.to_path_buf(),
+            line,
+            message,
+        },
+        None => IncludeError {
+            file: file.to_path_buf(),
+            line: 0,
+            message,
+        },
+    };
+
+    let canonical = file
+        .canonicalize()
+        .map_err(|e| error_at(format!("could not open {file:?}: {e}")))?;
+    if stack.contains(&canonical) {
+        return Err(error_at(format!("include cycle through {file:?}")));
+    }
+    if resolved.files.contains(&canonical) {
+        return Ok(());
+    }
+
+    let content = std::fs::read_to_string(&canonical)
+        .map_err(|e| error_at(format!("could not read {file:?}: {e}")))?;
+    let file_index = resolved.files.len();
+    resolved.files.push(canonical.clone());
+    resolved.contents.push(content.clone());
+    stack.push(canonical.clone());
+
+    let dir = canonical.parent().unwrap_or(Path::new("."));
+    for (i, line) in content.lines().enumerate() {
+        match parse_include(line) {
+            Some(Ok(path)) => {
+                resolve_into(&dir.join(path), Some((&canonical, i + 1)), resolved, stack)?
+            }
+            Some(Err(message)) => {
+                return Err(IncludeError {
+                    file: canonical.clone(),
+                    line: i + 1,
+                    message,
+                })
+            }
+            None => {
+                resolved.text.push_str(line);
+                resolved.text.push('\n');
+                resolved.lines.push((file_index, i + 1));
+            }
+        }
+    }
+
+    stack.pop();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Writes `files` (relative path and content) to a fresh directory and returns it.
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("msdf-include-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, content) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn parses_include_statements() {
+        assert_eq!(
+            parse_include("include \"a.minisdf\""),
+            Some(Ok("a.minisdf"))
+        );
+        assert_eq!(
+            parse_include("  include \"a.minisdf\";  "),
+            Some(Ok("a.minisdf"))
+        );
+        assert_eq!(
+            parse_include("include \"sub/a.minisdf\" // comment"),
+            Some(Ok("sub/a.minisdf"))
+        );
+        assert_eq!(
+            parse_include("include\"a.minisdf\"; //comment"),
+            Some(Ok("a.minisdf"))
+        );
+        assert_eq!(parse_include("field includes(a: float){"), None);
+        assert_eq!(parse_include("sphere(1.0)"), None);
+        assert!(matches!(parse_include("include a.minisdf"), Some(Err(_))));
+        assert!(matches!(parse_include("include \"\""), Some(Err(_))));
+        assert!(matches!(
+            parse_include("include \"a.minisdf\" b"),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn resolves_relative_includes_once() {
+        let dir = write_files(
+            "relative",
+            &[
+                (
+                    "main.minisdf",
+                    "include \"sub/a.minisdf\"\ninclude \"b.minisdf\"\nmain\n",
+                ),
+                ("sub/a.minisdf", "include \"../b.minisdf\"\na\n"),
+                ("b.minisdf", "b1\nb2\n"),
+            ],
+        );
+
+        let resolved = resolve_includes(&dir.join("main.minisdf")).unwrap();
+        assert_eq!(resolved.text, "b1\nb2\na\nmain\n");
+        assert_eq!(
+            resolved.files,
+            vec![
+                dir.join("main.minisdf"),
+                dir.join("sub/a.minisdf"),
+                dir.join("b.minisdf")
+            ]
+        );
+        assert_eq!(resolved.contents[2], "b1\nb2\n");
+        assert_eq!(resolved.lines, vec![(2, 1), (2, 2), (1, 2), (0, 3)]);
+
+        assert_eq!(
+            resolved.origin(2),
+            Some((dir.join("b.minisdf").as_path(), 2))
+        );
+        assert_eq!(
+            resolved.origin(4),
+            Some((dir.join("main.minisdf").as_path(), 3))
+        );
+        assert_eq!(resolved.origin(0), None);
+        assert_eq!(resolved.origin(5), None);
+
+        let mapped = resolved.map_lines("unexpected token at line 3, column 1; line 9");
+        assert_eq!(
+            mapped,
+            format!(
+                "unexpected token at {:?} line 2, column 1; line 9",
+                dir.join("sub/a.minisdf")
+            )
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_include_cycles() {
+        let dir = write_files(
+            "cycle",
+            &[
+                ("main.minisdf", "include \"a.minisdf\"\n"),
+                ("a.minisdf", "include \"b.minisdf\"\n"),
+                ("b.minisdf", "\ninclude \"a.minisdf\"\n"),
+            ],
+        );
+
+        let error = resolve_includes(&dir.join("main.minisdf")).unwrap_err();
+        assert_eq!(error.file, dir.join("b.minisdf"));
+        assert_eq!(error.line, 2);
+        assert!(error.message.contains("cycle"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,601 @@
+//! Device independent patch pipeline of the renderer.
+//!
+//! Compiles a `.minisdf` file via [msdfc](https://gitlab.com/tendsinmende/minisdf) and patches the resulting fields
+//! into a base shader using [spv-patcher](https://gitlab.com/tendsinmende/spv-patcher). The result is plain SPIR-V code
+//! that can be uploaded to a device, or written to disk.
+
+use std::{
+    fmt::Display,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+pub use cache::{CacheKey, PatchCache, PIPELINE_VERSION};
+pub use include::{resolve_includes, IncludeError, ResolvedSource};
+use patch_function::rspirv;
+pub use routing::{PatchPoint, PatchRouting, Route, DEFAULT_PATCH_POINT};
+pub use signature::Signature;
+pub use stage::{
+    default_stages, CapabilityFixup, Compile, MemoryModelFixup, PatchContext, PatchStage,
+    PatchState, ReplacePatchPoints, Specialize, Validate,
+};
+use thiserror::Error;
+pub use validate::{has_entry_point, validate, ValidationError};
+
+mod cache;
+mod include;
+mod routing;
+mod signature;
+mod stage;
+mod validate;
+
+const DEFAULT_BASE_SHADER: &[u8] = include_bytes!("../../../resources/generated/base-shader.spv");
+
+///The base sphere-tracing shader as built by `shader_builder`.
+pub static BASE_SHADER: &[u8] = DEFAULT_BASE_SHADER;
+
+///All variants of the base shader built by `shader_builder`, by name. The first one is [BASE_SHADER].
+pub static BASE_SHADER_VARIANTS: &[(&str, &[u8])] = &[
+    ("default", DEFAULT_BASE_SHADER),
+    (
+        "fast",
+        include_bytes!("../../../resources/generated/base-shader-fast.spv"),
+    ),
+    (
+        "high_quality",
+        include_bytes!("../../../resources/generated/base-shader-high_quality.spv"),
+    ),
+    (
+        "debug_renderer",
+        include_bytes!("../../../resources/generated/base-shader-debug_renderer.spv"),
+    ),
+];
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+///Compute entry point of the base shader.
+pub const ENTRY_POINT: &str = "renderer";
+
+///Optional compute entry point of the base shader, that cone-traces the start distances of [ENTRY_POINT]'s rays.
+pub const CONE_PREPASS_ENTRY_POINT: &str = "cone_prepass";
+
+///Converts little-endian SPIR-V bytes to words, without requiring `bytes` to be aligned.
+pub fn words_from_bytes(bytes: &[u8]) -> Option<Vec<u32>> {
+    if bytes.len() % 4 != 0 {
+        return None;
+    }
+
+    Some(
+        bytes
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect(),
+    )
+}
+
+///Checks that `base_shader` can be used by a [SdfPatchPipeline]. It needs to be valid SPIR-V with a
+/// [ENTRY_POINT] compute entry point and a [DEFAULT_PATCH_POINT] function.
+pub fn check_base_shader(base_shader: &[u8]) -> Result<(), PatchError> {
+    let code = words_from_bytes(base_shader)
+        .ok_or_else(|| PatchError::BaseShader("size is not a multiple of 4 bytes".to_owned()))?;
+    validate(&code, ENTRY_POINT).map_err(|e| PatchError::BaseShader(e.to_string()))?;
+
+    let module =
+        rspirv::dr::load_words(&code).map_err(|e| PatchError::BaseShader(e.to_string()))?;
+    if !PatchPoint::collect(&module)
+        .iter()
+        .any(|p| p.name == DEFAULT_PATCH_POINT)
+    {
+        return Err(PatchError::BaseShader(format!(
+            "no {DEFAULT_PATCH_POINT} patch point"
+        )));
+    }
+    Ok(())
+}
+
+///Location of a field in the `.minisdf` source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpan {
+    ///Byte range of the field's declaration within the combined source, including its body.
+    pub range: Range<usize>,
+    ///File the declaration is in, if known.
+    pub file: Option<PathBuf>,
+    ///1-based line within `file` the declaration starts at.
+    pub line: usize,
+}
+
+impl Display for SourceSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{file:?} line {}", self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+fn at_span(span: &Option<SourceSpan>) -> String {
+    match span {
+        Some(span) => format!(" at {span}"),
+        None => String::new(),
+    }
+}
+
+///Error of a single stage of the [SdfPatchPipeline].
+#[derive(Debug, Clone, Error)]
+pub enum PatchError {
+    #[error("Could not load base shader: {0}")]
+    BaseShader(String),
+    #[error("Failed to resolve includes: {0}")]
+    Include(#[from] IncludeError),
+    #[error("Failed to compile {file:?}: {error}")]
+    Compile { file: String, error: String },
+    #[error("Route for field {field} exists, but the file has no such field")]
+    UnknownField { field: String },
+    #[error("Field {field}{} has no route to a patch point", at_span(.span))]
+    UnmappedField {
+        field: String,
+        span: Option<SourceSpan>,
+    },
+    #[error(
+        "Field {field} is routed to {patch_point}, but the base shader has no such patch point"
+    )]
+    UnknownPatchPoint { field: String, patch_point: String },
+    #[error("Fields {first} and {second} are both routed to patch point {patch_point}")]
+    DuplicatePatchPoint {
+        patch_point: String,
+        first: String,
+        second: String,
+    },
+    #[error("Field {field}{} does not match patch point {patch_point}: {mismatch}", at_span(.span))]
+    Signature {
+        field: String,
+        span: Option<SourceSpan>,
+        patch_point: String,
+        mismatch: String,
+    },
+    #[error("Failed to mutate memory model for field {field}{}: {error}", at_span(.span))]
+    MemoryModel {
+        field: String,
+        span: Option<SourceSpan>,
+        error: String,
+    },
+    #[error("Failed to build patch for field {field}{}: {error}", at_span(.span))]
+    StaticReplace {
+        field: String,
+        span: Option<SourceSpan>,
+        error: String,
+    },
+    #[error("Failed to patch field {field}{}: {error}", at_span(.span))]
+    Patch {
+        field: String,
+        span: Option<SourceSpan>,
+        error: String,
+    },
+    #[error("Failed to assemble patched module: {0}")]
+    Assemble(String),
+    #[error("Stage {stage} failed: {error}")]
+    Stage { stage: String, error: String },
+    #[error("Patched module is invalid: {0}")]
+    Validation(#[from] ValidationError),
+    #[error("Source changed while patching, result discarded")]
+    Outdated,
+    #[error("Could not build shader module for patched shader: {0}")]
+    ModuleCreation(String),
+}
+
+impl PatchError {
+    ///Name of the field that failed to patch, if the error is related to a single field.
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            PatchError::UnknownField { field }
+            | PatchError::UnmappedField { field, .. }
+            | PatchError::UnknownPatchPoint { field, .. }
+            | PatchError::Signature { field, .. }
+            | PatchError::MemoryModel { field, .. }
+            | PatchError::StaticReplace { field, .. }
+            | PatchError::Patch { field, .. } => Some(field),
+            _ => None,
+        }
+    }
+}
+
+///Source and per-field SPIR-V modules of a compiled `.minisdf` file.
+pub struct CompiledSdf {
+    pub source: ResolvedSource,
+    pub fields: Vec<(String, Vec<u32>)>,
+}
+
+impl CompiledSdf {
+    ///Searches the declaration `field <name>(...){...}` in the source.
+    pub fn field_span(&self, name: &str) -> Option<SourceSpan> {
+        let src = self.source.text.as_str();
+        let mut search = 0;
+        while let Some(offset) = src[search..].find("field") {
+            let start = search + offset;
+            search = start + "field".len();
+
+            let is_word_start = src[..start]
+                .chars()
+                .last()
+                .map(|c| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(true);
+            let rest = &src[search..];
+            let ident = rest.trim_start();
+            if !is_word_start
+                || ident.len() == rest.len()
+                || !ident.starts_with(name)
+                || !ident[name.len()..].trim_start().starts_with('(')
+            {
+                continue;
+            }
+
+            //Find the end of the field's body by matching braces.
+            let mut end = src.len();
+            let mut depth = 0usize;
+            if let Some(body) = src[start..].find('{') {
+                for (i, c) in src[start + body..].char_indices() {
+                    match c {
+                        '{' => depth += 1,
+                        '}' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                end = start + body + i + 1;
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            let line = src[..start].matches('\n').count() + 1;
+            let (file, line) = match self.source.origin(line) {
+                Some((file, line)) => (Some(file.to_path_buf()), line),
+                None => (None, line),
+            };
+            return Some(SourceSpan {
+                range: start..end,
+                file,
+                line,
+            });
+        }
+
+        None
+    }
+}
+
+///Time spent in each stage of a patch.
+#[derive(Debug, Clone, Default)]
+pub struct StageTimings {
+    ///Set if the module was loaded from the cache. No stage was run in that case.
+    pub cached: Option<Duration>,
+    ///Name and duration of each [PatchStage], in pipeline order.
+    pub stages: Vec<(String, Duration)>,
+    ///Filled in by the user of the pipeline, when uploading the module to a device.
+    pub module_creation: Duration,
+}
+
+impl StageTimings {
+    pub fn total(&self) -> Duration {
+        self.cached.unwrap_or_default()
+            + self.stages.iter().map(|(_, d)| *d).sum::<Duration>()
+            + self.module_creation
+    }
+}
+
+impl Display for StageTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f32() * 1000.0;
+        write!(f, "{:.2}ms (", ms(self.total()))?;
+        if let Some(cached) = self.cached {
+            write!(f, "cache {:.2}ms, ", ms(cached))?;
+        }
+        for (stage, duration) in &self.stages {
+            write!(f, "{stage} {:.2}ms, ", ms(*duration))?;
+        }
+        write!(f, "module creation {:.2}ms)", ms(self.module_creation))
+    }
+}
+
+///Patched base shader, and the `.minisdf` source it was patched with.
+pub struct PatchedSdf {
+    pub source: ResolvedSource,
+    pub code: Vec<u32>,
+    pub timings: StageTimings,
+}
+
+///Turns minisdf code into patched SPIR-V words, without touching any device.
+///
+/// The work is done by an ordered list of [PatchStage]s, see [default_stages].
+pub struct SdfPatchPipeline {
+    base: spv_patcher::Module,
+    base_code: Vec<u8>,
+    patch_points: Vec<PatchPoint>,
+    routing: PatchRouting,
+    cache: Option<PatchCache>,
+    stages: Vec<Box<dyn PatchStage>>,
+}
+
+impl SdfPatchPipeline {
+    ///Creates the pipeline for the given base shader code.
+    pub fn new(base_shader: &[u8]) -> Result<Self, PatchError> {
+        let (base, patch_points) = Self::load_base(base_shader)?;
+        Ok(SdfPatchPipeline {
+            base,
+            base_code: base_shader.to_vec(),
+            patch_points,
+            routing: PatchRouting::default(),
+            cache: None,
+            stages: default_stages(),
+        })
+    }
+
+    fn load_base(base_shader: &[u8]) -> Result<(spv_patcher::Module, Vec<PatchPoint>), PatchError> {
+        check_base_shader(base_shader)?;
+        let base = spv_patcher::Module::new(base_shader.to_vec())
+            .map_err(|e| PatchError::BaseShader(e.to_string()))?;
+        let patch_points = rspirv::dr::load_bytes(base_shader)
+            .map(|module| PatchPoint::collect(&module))
+            .map_err(|e| PatchError::BaseShader(e.to_string()))?;
+        Ok((base, patch_points))
+    }
+
+    ///Replaces the base shader, keeping routing, cache and stages. On error the old base shader is kept.
+    pub fn set_base_shader(&mut self, base_shader: &[u8]) -> Result<(), PatchError> {
+        let (base, patch_points) = Self::load_base(base_shader)?;
+        self.base = base;
+        self.base_code = base_shader.to_vec();
+        self.patch_points = patch_points;
+        Ok(())
+    }
+
+    ///Sets the field to patch point routing.
+    pub fn with_routing(mut self, routing: PatchRouting) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    ///Loads patched modules from `cache` if possible, and stores newly patched ones.
+    pub fn with_cache(mut self, cache: PatchCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    ///Replaces all stages. They are run in the given order.
+    pub fn with_stages(mut self, stages: Vec<Box<dyn PatchStage>>) -> Self {
+        self.stages = stages;
+        self
+    }
+
+    ///Inserts `stage` before the first stage named `before`, or appends it if there is no such stage.
+    pub fn with_stage_before(mut self, before: &str, stage: impl PatchStage + 'static) -> Self {
+        let index = self
+            .stages
+            .iter()
+            .position(|s| s.name() == before)
+            .unwrap_or(self.stages.len());
+        self.stages.insert(index, Box::new(stage));
+        self
+    }
+
+    ///Names of all stages, in the order they are run.
+    pub fn stages(&self) -> impl Iterator<Item = &str> {
+        self.stages.iter().map(|s| s.name())
+    }
+
+    fn cache_key(&self, source: &str) -> CacheKey {
+        let stages = self.stages.iter().map(|s| s.cache_id()).collect::<Vec<_>>();
+        CacheKey::new(
+            source,
+            &self.base_code,
+            &format!("{:?}{:?}", self.routing.routes, stages),
+        )
+    }
+
+    ///All functions of the base shader that can be replaced.
+    pub fn patch_points(&self) -> &[PatchPoint] {
+        &self.patch_points
+    }
+
+    ///Compiles `file` and patches all fields into the base shader.
+    pub fn patch_file(&self, file: impl AsRef<Path>) -> Result<PatchedSdf, PatchError> {
+        self.patch_file_with(file, &|| false)
+    }
+
+    ///Like [Self::patch_file], but checks `is_outdated` between stages, and returns [PatchError::Outdated]
+    /// as soon as it returns true. Lets long running patches be abandoned if the source changed in the meantime.
+    pub fn patch_file_with(
+        &self,
+        file: impl AsRef<Path>,
+        is_outdated: &dyn Fn() -> bool,
+    ) -> Result<PatchedSdf, PatchError> {
+        let mut timings = StageTimings::default();
+        let start = Instant::now();
+        let source = resolve_includes(file.as_ref())?;
+        let key = self.cache_key(&source.text);
+        if let Some(code) = self.cache.as_ref().and_then(|cache| cache.load(&key)) {
+            match validate(&code, ENTRY_POINT) {
+                Ok(()) => {
+                    log::info!("Using cached module {}", key.as_str());
+                    timings.cached = Some(start.elapsed());
+                    return Ok(PatchedSdf {
+                        source,
+                        code,
+                        timings,
+                    });
+                }
+                Err(e) => log::warn!("Ignoring invalid cache entry {}: {e}", key.as_str()),
+            }
+        }
+
+        let mut state = PatchState {
+            file: file.as_ref().to_path_buf(),
+            compiled: CompiledSdf {
+                source,
+                fields: Vec::new(),
+            },
+            code: Vec::new(),
+        };
+        let ctx = PatchContext {
+            base: &self.base,
+            patch_points: &self.patch_points,
+            routing: &self.routing,
+        };
+        for stage in &self.stages {
+            let start = Instant::now();
+            stage.run(&ctx, &mut state)?;
+            timings
+                .stages
+                .push((stage.name().to_owned(), start.elapsed()));
+
+            if is_outdated() {
+                return Err(PatchError::Outdated);
+            }
+        }
+
+        if let Some(cache) = &self.cache {
+            cache.store(&key, &state.code);
+        }
+        Ok(PatchedSdf {
+            source: state.compiled.source,
+            code: state.code,
+            timings,
+        })
+    }
+
+    ///Compiles `file`, including all files it includes, into one SPIR-V module per field.
+    pub fn compile(file: impl AsRef<Path>) -> Result<CompiledSdf, PatchError> {
+        let source = resolve_includes(file.as_ref())?;
+        let fields = compile_fields(file.as_ref(), &source)?;
+        Ok(CompiledSdf { source, fields })
+    }
+}
+
+///Compiles the resolved `source` of `file` into one SPIR-V module per field.
+fn compile_fields(
+    file: &Path,
+    source: &ResolvedSource,
+) -> Result<Vec<(String, Vec<u32>)>, PatchError> {
+    //NOTE: msdfc compiles files, so if there are includes, the combined program is written to a
+    //      temporary file first.
+    let compile_file = if source.files.len() > 1 {
+        let dir = std::env::temp_dir().join("msdf-renderer");
+        let combined = dir.join(format!(
+            "{}-{}.combined.minisdf",
+            file.file_stem()
+                .map(|s| s.to_string_lossy())
+                .unwrap_or_default(),
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir)
+            .and_then(|_| std::fs::write(&combined, &source.text))
+            .map_err(|e| PatchError::Compile {
+                file: combined.to_string_lossy().into_owned(),
+                error: e.to_string(),
+            })?;
+        combined
+    } else {
+        file.to_path_buf()
+    };
+
+    //NOTE: Lines of the combined file are mapped back to the included files they come from.
+    let fields = msdfc::compile_file(&compile_file)
+        .map_err(|e| PatchError::Compile {
+            file: file.to_string_lossy().into_owned(),
+            error: if compile_file == file {
+                e.to_string()
+            } else {
+                source.map_lines(&e.to_string())
+            },
+        })?
+        .into_iter()
+        .collect();
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shader_builder::reflect::PushConstantLayout;
+
+    #[test]
+    fn push_constants_match_render_uniform() {
+        let layout = PushConstantLayout::reflect(&words_from_bytes(BASE_SHADER).unwrap())
+            .unwrap()
+            .expect("base shader has no push constants");
+        if let Err(e) = layout.check(&shared::RenderUniform::field_layout()) {
+            panic!("RenderUniform does not match the base shader's push constants:\n{e}");
+        }
+    }
+
+    #[test]
+    fn variants_match_shader_builder() {
+        let names = BASE_SHADER_VARIANTS
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        let built = shader_builder::variants::VARIANTS
+            .iter()
+            .map(|variant| variant.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, built);
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn patches_fixture() {
+        let pipeline = SdfPatchPipeline::new(BASE_SHADER).unwrap();
+        let patched = match pipeline.patch_file(fixture("sphere.minisdf")) {
+            Ok(patched) => patched,
+            Err(e) => panic!("{e}"),
+        };
+
+        validate(&patched.code, ENTRY_POINT).unwrap();
+        assert_ne!(patched.code, words_from_bytes(BASE_SHADER).unwrap());
+        assert_eq!(patched.source.files.len(), 1);
+        assert!(patched.timings.cached.is_none());
+        assert!(patched
+            .timings
+            .stages
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .eq(pipeline.stages()));
+    }
+
+    #[test]
+    fn rejects_mismatching_field() {
+        let pipeline = SdfPatchPipeline::new(BASE_SHADER).unwrap();
+        match pipeline.patch_file(fixture("wrong_signature.minisdf")) {
+            Err(PatchError::Signature { field, span, .. }) => {
+                assert_eq!(field, "bad");
+                assert_eq!(span.map(|s| s.line), Some(1));
+            }
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("patched a field with the wrong signature"),
+        }
+    }
+
+    #[test]
+    fn finds_field_span() {
+        let compiled = CompiledSdf {
+            source: ResolvedSource {
+                text: "//myfield\nfield other(a: float){ sphere(a) }\nfield myfield(offset: vec3){\n    translate(offset){\n        sphere(1.0)\n    }\n}\n"
+                .to_owned(),
+                ..Default::default()
+            },
+            fields: Vec::new(),
+        };
+
+        let span = compiled.field_span("myfield").unwrap();
+        assert_eq!(span.line, 3);
+        assert!(compiled.source.text[span.range.clone()].starts_with("field myfield"));
+        assert!(compiled.source.text[span.range].ends_with("}\n}"));
+        assert!(compiled.field_span("missing").is_none());
+    }
+}
@@ -0,0 +1,195 @@
+//! Maps the fields of a `.minisdf` file to the functions of the base shader they replace.
+
+use std::str::FromStr;
+
+use patch_function::rspirv::dr::{self, Operand};
+
+use crate::{signature::Signature, CompiledSdf, PatchError};
+
+///Patch point that is used if a file only contains a single, unmapped field.
+pub const DEFAULT_PATCH_POINT: &str = "eval_sdf";
+
+///Function of the base shader that can be replaced by a field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchPoint {
+    pub name: String,
+    ///Index of the function within the base module.
+    pub function_index: usize,
+    ///Result id of the function within the base module.
+    pub function_id: u32,
+    pub signature: Option<Signature>,
+}
+
+impl PatchPoint {
+    ///Collects all functions of `module` that have a debug name, keyed by their unmangled name.
+    ///
+    /// rust-gpu names functions by their path, so `base_shader::eval_sdf` is found as `eval_sdf`.
+    pub fn collect(module: &dr::Module) -> Vec<PatchPoint> {
+        module
+            .functions
+            .iter()
+            .enumerate()
+            .filter_map(|(function_index, f)| {
+                let function_id = f.def_id()?;
+                let name =
+                    module
+                        .debug_names
+                        .iter()
+                        .find_map(|inst| match inst.operands.as_slice() {
+                            [Operand::IdRef(id), Operand::LiteralString(name)]
+                                if *id == function_id =>
+                            {
+                                Some(name.rsplit("::").next().unwrap_or(name).to_owned())
+                            }
+                            _ => None,
+                        })?;
+
+                Some(PatchPoint {
+                    name,
+                    function_index,
+                    function_id,
+                    signature: Signature::of_function(module, f),
+                })
+            })
+            .collect()
+    }
+}
+
+///A single `field=patch_point` route.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub field: String,
+    pub patch_point: String,
+}
+
+impl FromStr for Route {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((field, patch_point)) if !field.is_empty() && !patch_point.is_empty() => {
+                Ok(Route {
+                    field: field.trim().to_owned(),
+                    patch_point: patch_point.trim().to_owned(),
+                })
+            }
+            _ => Err(format!("expected `field=patch_point`, got \"{s}\"")),
+        }
+    }
+}
+
+///Explicit mapping of field names to patch points.
+///
+/// Fields without a route are patched into the patch point of the same name. A file with a single
+/// field that matches no patch point is patched into [DEFAULT_PATCH_POINT].
+#[derive(Debug, Clone, Default)]
+pub struct PatchRouting {
+    pub routes: Vec<Route>,
+}
+
+impl PatchRouting {
+    pub fn new(routes: Vec<Route>) -> Self {
+        PatchRouting { routes }
+    }
+
+    ///Resolves the patch point for every field in `compiled`. Returns `(field_index, patch_point)` pairs.
+    pub fn resolve<'a>(
+        &self,
+        compiled: &CompiledSdf,
+        points: &'a [PatchPoint],
+    ) -> Result<Vec<(usize, &'a PatchPoint)>, PatchError> {
+        for route in &self.routes {
+            if !compiled.fields.iter().any(|(name, _)| name == &route.field) {
+                return Err(PatchError::UnknownField {
+                    field: route.field.clone(),
+                });
+            }
+        }
+
+        let find_point = |name: &str| points.iter().find(|p| p.name == name);
+
+        let mut resolved: Vec<(usize, &PatchPoint)> = Vec::with_capacity(compiled.fields.len());
+        for (field_index, (field, _)) in compiled.fields.iter().enumerate() {
+            let point = if let Some(route) = self.routes.iter().find(|r| &r.field == field) {
+                find_point(&route.patch_point).ok_or_else(|| PatchError::UnknownPatchPoint {
+                    field: field.clone(),
+                    patch_point: route.patch_point.clone(),
+                })?
+            } else if let Some(point) = find_point(field) {
+                point
+            } else if compiled.fields.len() == 1 {
+                find_point(DEFAULT_PATCH_POINT).ok_or_else(|| PatchError::UnknownPatchPoint {
+                    field: field.clone(),
+                    patch_point: DEFAULT_PATCH_POINT.to_owned(),
+                })?
+            } else {
+                return Err(PatchError::UnmappedField {
+                    field: field.clone(),
+                    span: compiled.field_span(field),
+                });
+            };
+
+            if let Some((other, _)) = resolved.iter().find(|(_, p)| p.name == point.name) {
+                return Err(PatchError::DuplicatePatchPoint {
+                    patch_point: point.name.clone(),
+                    first: compiled.fields[*other].0.clone(),
+                    second: field.clone(),
+                });
+            }
+            resolved.push((field_index, point));
+        }
+
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(name: &str, function_index: usize) -> PatchPoint {
+        PatchPoint {
+            name: name.to_owned(),
+            function_index,
+            function_id: function_index as u32 + 1,
+            signature: None,
+        }
+    }
+
+    fn compiled(fields: &[&str]) -> CompiledSdf {
+        CompiledSdf {
+            source: Default::default(),
+            fields: fields.iter().map(|f| (f.to_string(), Vec::new())).collect(),
+        }
+    }
+
+    #[test]
+    fn resolves_routes() {
+        let points = [point("eval_sdf", 0), point("eval_color", 1)];
+
+        let single = PatchRouting::default()
+            .resolve(&compiled(&["myfield"]), &points)
+            .unwrap();
+        assert_eq!(single[0].1.name, "eval_sdf");
+
+        let routing = PatchRouting::new(vec!["a=eval_color".parse().unwrap()]);
+        let routed = routing
+            .resolve(&compiled(&["a", "eval_sdf"]), &points)
+            .unwrap();
+        assert_eq!(routed[0].1.name, "eval_color");
+        assert_eq!(routed[1].1.name, "eval_sdf");
+
+        assert!(matches!(
+            PatchRouting::default().resolve(&compiled(&["a", "b"]), &points),
+            Err(PatchError::UnmappedField { .. })
+        ));
+        assert!(matches!(
+            PatchRouting::new(vec!["a=eval_sdf".parse().unwrap()])
+                .resolve(&compiled(&["a", "eval_sdf"]), &points),
+            Err(PatchError::DuplicatePatchPoint { .. })
+        ));
+        assert!(matches!(
+            routing.resolve(&compiled(&["b"]), &points),
+            Err(PatchError::UnknownField { .. })
+        ));
+    }
+}
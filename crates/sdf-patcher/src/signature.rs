@@ -0,0 +1,183 @@
+//! Readable function signatures, used to check a field against the patch point it replaces.
+
+use std::fmt::Display;
+
+use patch_function::rspirv::{
+    dr::{self, Operand},
+    spirv::{Op, Word},
+};
+
+///Function signature with all types resolved to readable names like `float` or `vec3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub ret: String,
+    pub params: Vec<String>,
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "fn({}) -> {}", self.params.join(", "), self.ret)
+    }
+}
+
+impl Signature {
+    ///Reads the signature of `function` in `module`.
+    pub fn of_function(module: &dr::Module, function: &dr::Function) -> Option<Self> {
+        let function_type = match function.def.as_ref()?.operands.get(1)? {
+            Operand::IdRef(id) => *id,
+            _ => return None,
+        };
+        let type_inst = find_type(module, function_type)?;
+        if type_inst.class.opcode != Op::TypeFunction {
+            return None;
+        }
+
+        let mut types = type_inst.operands.iter().map(|op| match op {
+            Operand::IdRef(id) => type_name(module, *id),
+            _ => "<unknown>".to_owned(),
+        });
+
+        Some(Signature {
+            ret: types.next()?,
+            params: types.collect(),
+        })
+    }
+
+    ///Describes the first difference to `expected`, if there is any.
+    pub fn mismatch(&self, expected: &Signature) -> Option<String> {
+        if self.params.len() != expected.params.len() {
+            return Some(format!(
+                "has {} parameters, expected {}",
+                self.params.len(),
+                expected.params.len()
+            ));
+        }
+
+        for (i, (found, expected)) in self.params.iter().zip(&expected.params).enumerate() {
+            if found != expected {
+                return Some(format!(
+                    "parameter {} is {found}, expected {expected}",
+                    i + 1
+                ));
+            }
+        }
+
+        if self.ret != expected.ret {
+            return Some(format!("returns {}, expected {}", self.ret, expected.ret));
+        }
+
+        None
+    }
+}
+
+fn find_type(module: &dr::Module, id: Word) -> Option<&dr::Instruction> {
+    module
+        .types_global_values
+        .iter()
+        .find(|inst| inst.result_id == Some(id))
+}
+
+fn type_name(module: &dr::Module, id: Word) -> String {
+    let inst = match find_type(module, id) {
+        Some(inst) => inst,
+        None => return format!("<undefined %{id}>"),
+    };
+
+    match (inst.class.opcode, inst.operands.as_slice()) {
+        (Op::TypeVoid, _) => "void".to_owned(),
+        (Op::TypeBool, _) => "bool".to_owned(),
+        (Op::TypeFloat, [Operand::LiteralBit32(32), ..]) => "float".to_owned(),
+        (Op::TypeFloat, [Operand::LiteralBit32(bits), ..]) => format!("f{bits}"),
+        (Op::TypeInt, [Operand::LiteralBit32(32), Operand::LiteralBit32(0)]) => "uint".to_owned(),
+        (Op::TypeInt, [Operand::LiteralBit32(32), Operand::LiteralBit32(_)]) => "int".to_owned(),
+        (Op::TypeInt, [Operand::LiteralBit32(bits), Operand::LiteralBit32(0)]) => {
+            format!("u{bits}")
+        }
+        (Op::TypeInt, [Operand::LiteralBit32(bits), _]) => format!("i{bits}"),
+        (Op::TypeVector, [Operand::IdRef(component), Operand::LiteralBit32(count)]) => {
+            match type_name(module, *component).as_str() {
+                "float" => format!("vec{count}"),
+                "int" => format!("ivec{count}"),
+                "uint" => format!("uvec{count}"),
+                other => format!("{other}x{count}"),
+            }
+        }
+        (Op::TypePointer, [_, Operand::IdRef(pointee)]) => {
+            format!("ptr<{}>", type_name(module, *pointee))
+        }
+        (op, _) => format!("{op:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use patch_function::rspirv::spirv::FunctionControl;
+
+    ///Builds a module with a function of type `fn(params) -> ret` for each signature, types are named with `float`,
+    /// `vec3` and `uint`. Returns the signature read from each function.
+    fn read_signatures(signatures: &[(&str, &[&str])]) -> Vec<Signature> {
+        let mut b = dr::Builder::new();
+        let float = b.type_float(32);
+        let vec3 = b.type_vector(float, 3);
+        let uint = b.type_int(32, 0);
+        let ty = |name: &str| match name {
+            "float" => float,
+            "vec3" => vec3,
+            "uint" => uint,
+            other => panic!("unknown type {other}"),
+        };
+
+        for (ret, params) in signatures {
+            let params = params.iter().map(|p| ty(p)).collect::<Vec<_>>();
+            let function_type = b.type_function(ty(ret), params.clone());
+            b.begin_function(ty(ret), None, FunctionControl::NONE, function_type)
+                .unwrap();
+            for param in params {
+                b.function_parameter(param).unwrap();
+            }
+            b.begin_block(None).unwrap();
+            b.unreachable().unwrap();
+            b.end_function().unwrap();
+        }
+
+        let module = b.module();
+        module
+            .functions
+            .iter()
+            .map(|f| Signature::of_function(&module, f).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn reads_readable_signatures() {
+        let signatures = read_signatures(&[("float", &["vec3", "uint"])]);
+        assert_eq!(signatures[0].to_string(), "fn(vec3, uint) -> float");
+    }
+
+    #[test]
+    fn reports_first_mismatch() {
+        let signatures = read_signatures(&[
+            ("float", &["vec3", "vec3"]),
+            ("float", &["vec3"]),
+            ("float", &["vec3", "float"]),
+            ("vec3", &["vec3", "vec3"]),
+            ("float", &["vec3", "vec3"]),
+        ]);
+        let expected = &signatures[0];
+
+        assert_eq!(
+            signatures[1].mismatch(expected).unwrap(),
+            "has 1 parameters, expected 2"
+        );
+        assert_eq!(
+            signatures[2].mismatch(expected).unwrap(),
+            "parameter 2 is float, expected vec3"
+        );
+        assert_eq!(
+            signatures[3].mismatch(expected).unwrap(),
+            "returns vec3, expected float"
+        );
+        assert_eq!(signatures[4].mismatch(expected), None);
+    }
+}
@@ -0,0 +1,423 @@
+//! Stages of the [SdfPatchPipeline](crate::SdfPatchPipeline).
+//!
+//! Each stage transforms the [PatchState] that is passed through the pipeline. The default pipeline is
+//! [Compile] → [MemoryModelFixup] → [ReplacePatchPoints] → [CapabilityFixup] → [Validate]. Own stages, like
+//! instrumentation or constant folding, can be inserted anywhere via
+//! [SdfPatchPipeline::with_stage_before](crate::SdfPatchPipeline::with_stage_before).
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use patch_function::{
+    rspirv::{
+        self,
+        binary::Assemble,
+        dr::{self, Operand},
+        spirv::{Decoration, Op},
+    },
+    StaticReplace,
+};
+
+use crate::{
+    compile_fields, validate, CompiledSdf, PatchError, PatchPoint, PatchRouting, Signature,
+    ENTRY_POINT, SPIRV_MAGIC,
+};
+
+///Base shader and configuration of the pipeline a stage runs in.
+pub struct PatchContext<'a> {
+    pub base: &'a spv_patcher::Module,
+    pub patch_points: &'a [PatchPoint],
+    pub routing: &'a PatchRouting,
+}
+
+///Data that is passed through all stages.
+pub struct PatchState {
+    ///The `.minisdf` file that is patched.
+    pub file: PathBuf,
+    ///Resolved source of `file`. The fields are filled in by [Compile].
+    pub compiled: CompiledSdf,
+    ///The patched module. Empty until the fields were patched into the base shader by [ReplacePatchPoints].
+    pub code: Vec<u32>,
+}
+
+///A single transformation of the [SdfPatchPipeline](crate::SdfPatchPipeline).
+pub trait PatchStage: Send + Sync {
+    ///Name of the stage, used in timings and logs.
+    fn name(&self) -> &str;
+
+    ///Identifies the stage's configuration within cache keys. Stages whose output depends on their
+    /// configuration must include it here, so differently configured pipelines don't share cache entries.
+    fn cache_id(&self) -> String {
+        self.name().to_owned()
+    }
+
+    fn run(&self, ctx: &PatchContext, state: &mut PatchState) -> Result<(), PatchError>;
+}
+
+///The stages of a newly created pipeline.
+pub fn default_stages() -> Vec<Box<dyn PatchStage>> {
+    vec![
+        Box::new(Compile),
+        Box::new(MemoryModelFixup),
+        Box::new(ReplacePatchPoints),
+        Box::new(CapabilityFixup),
+        Box::new(Validate),
+    ]
+}
+
+///Compiles the resolved source into one module per field using msdfc.
+pub struct Compile;
+
+impl PatchStage for Compile {
+    fn name(&self) -> &str {
+        "msdfc"
+    }
+
+    fn run(&self, _ctx: &PatchContext, state: &mut PatchState) -> Result<(), PatchError> {
+        state.compiled.fields = compile_fields(&state.file, &state.compiled.source)?;
+        Ok(())
+    }
+}
+
+///Moves each field's module from the GLSL450 to the Vulkan memory model used by the base shader.
+pub struct MemoryModelFixup;
+
+impl MemoryModelFixup {
+    pub fn apply(module: &[u32]) -> Result<Vec<u32>, String> {
+        let mmpatcher = spv_patcher::Module::new(bytemuck::cast_slice(module).to_vec())
+            .map_err(|e| format!("could not load module into patcher: {e}"))?;
+
+        let patched = mmpatcher
+            .patch()
+            .patch(spv_patcher::patch::MemoryModel {
+                from: (
+                    rspirv::spirv::AddressingModel::Logical,
+                    rspirv::spirv::MemoryModel::GLSL450,
+                ),
+                to: (
+                    rspirv::spirv::AddressingModel::Logical,
+                    rspirv::spirv::MemoryModel::Vulkan,
+                ),
+            })
+            .map_err(|e| e.to_string())?;
+
+        Ok(patched.assemble())
+    }
+}
+
+impl PatchStage for MemoryModelFixup {
+    fn name(&self) -> &str {
+        "memory model"
+    }
+
+    fn run(&self, _ctx: &PatchContext, state: &mut PatchState) -> Result<(), PatchError> {
+        for i in 0..state.compiled.fields.len() {
+            let (name, module) = &state.compiled.fields[i];
+            let fixed = Self::apply(module).map_err(|error| PatchError::MemoryModel {
+                field: name.clone(),
+                span: state.compiled.field_span(name),
+                error,
+            })?;
+            state.compiled.fields[i].1 = fixed;
+        }
+        Ok(())
+    }
+}
+
+///Routes the fields to their patch points, checks their signatures and replaces the patch points with them.
+pub struct ReplacePatchPoints;
+
+impl ReplacePatchPoints {
+    ///Compares the signature of the field's function against the patch point's signature.
+    fn check_signature(name: &str, module: &[u32], point: &PatchPoint) -> Result<(), String> {
+        let expected = match &point.signature {
+            Some(sig) => sig,
+            None => {
+                log::warn!(
+                    "No signature for patch point {}, skipping check",
+                    point.name
+                );
+                return Ok(());
+            }
+        };
+
+        let field_module =
+            rspirv::dr::load_words(module).map_err(|e| format!("could not parse module: {e}"))?;
+        let points = PatchPoint::collect(&field_module);
+        //NOTE: Fall back to the first function, if msdfc did not name the field's function.
+        let function = match points.iter().find(|p| p.name == name) {
+            Some(p) => &field_module.functions[p.function_index],
+            None => field_module
+                .functions
+                .first()
+                .ok_or_else(|| "module contains no function".to_owned())?,
+        };
+        let found = Signature::of_function(&field_module, function)
+            .ok_or_else(|| "could not read function signature".to_owned())?;
+
+        match found.mismatch(expected) {
+            Some(mismatch) => Err(mismatch),
+            None => Ok(()),
+        }
+    }
+}
+
+impl PatchStage for ReplacePatchPoints {
+    fn name(&self) -> &str {
+        "patch"
+    }
+
+    fn run(&self, ctx: &PatchContext, state: &mut PatchState) -> Result<(), PatchError> {
+        let compiled = &state.compiled;
+        let routes = ctx.routing.resolve(compiled, ctx.patch_points)?;
+
+        let mut patch = ctx.base.patch();
+        for (field_index, point) in routes {
+            let (name, module) = &compiled.fields[field_index];
+            log::info!("Injecting module {name} into {}", point.name);
+
+            Self::check_signature(name, module, point).map_err(|mismatch| {
+                PatchError::Signature {
+                    field: name.clone(),
+                    span: compiled.field_span(name),
+                    patch_point: point.name.clone(),
+                    mismatch,
+                }
+            })?;
+
+            let static_patch =
+                StaticReplace::new_from_bytes(bytemuck::cast_slice(module), point.function_index)
+                    .map_err(|e| PatchError::StaticReplace {
+                    field: name.clone(),
+                    span: compiled.field_span(name),
+                    error: e.to_string(),
+                })?;
+
+            patch = patch.patch(static_patch).map_err(|e| PatchError::Patch {
+                field: name.clone(),
+                span: compiled.field_span(name),
+                error: e.to_string(),
+            })?;
+        }
+
+        let code = patch.assemble();
+        match code.first() {
+            Some(&SPIRV_MAGIC) => {}
+            Some(magic) => {
+                return Err(PatchError::Assemble(format!(
+                    "invalid magic number {magic:#x}"
+                )))
+            }
+            None => return Err(PatchError::Assemble("module is empty".to_owned())),
+        }
+
+        state.code = code;
+        Ok(())
+    }
+}
+
+fn load_patched(stage: &str, code: &[u32]) -> Result<dr::Module, PatchError> {
+    if code.is_empty() {
+        return Err(PatchError::Stage {
+            stage: stage.to_owned(),
+            error: "needs to run after the fields were patched into the base shader".to_owned(),
+        });
+    }
+
+    dr::load_words(code).map_err(|e| PatchError::Stage {
+        stage: stage.to_owned(),
+        error: e.to_string(),
+    })
+}
+
+///Declares all capabilities the patched in fields use, but the base shader did not declare.
+pub struct CapabilityFixup;
+
+impl PatchStage for CapabilityFixup {
+    fn name(&self) -> &str {
+        "capabilities"
+    }
+
+    fn run(&self, _ctx: &PatchContext, state: &mut PatchState) -> Result<(), PatchError> {
+        let mut module = load_patched(self.name(), &state.code)?;
+        let mut added = false;
+        for (name, field) in &state.compiled.fields {
+            let field_module = dr::load_words(field).map_err(|e| PatchError::Stage {
+                stage: self.name().to_owned(),
+                error: format!("could not parse field {name}: {e}"),
+            })?;
+
+            for capability in field_module.capabilities {
+                if !module
+                    .capabilities
+                    .iter()
+                    .any(|c| c.operands == capability.operands)
+                {
+                    log::info!("Field {name} adds {:?}", capability.operands);
+                    module.capabilities.push(capability);
+                    added = true;
+                }
+            }
+        }
+
+        if added {
+            state.code = module.assemble();
+        }
+        Ok(())
+    }
+}
+
+///Replaces specialization constants with fixed values, so the driver can fold them.
+#[derive(Debug, Clone, Default)]
+pub struct Specialize {
+    ///32-bit values by `SpecId`. Booleans are `false` for 0, `true` otherwise.
+    pub constants: BTreeMap<u32, u32>,
+}
+
+impl Specialize {
+    pub fn with(mut self, spec_id: u32, value: u32) -> Self {
+        self.constants.insert(spec_id, value);
+        self
+    }
+
+    pub fn with_f32(self, spec_id: u32, value: f32) -> Self {
+        self.with(spec_id, value.to_bits())
+    }
+
+    pub fn apply(&self, module: &mut dr::Module) -> Result<(), String> {
+        //Result id of each specialized constant, and its new value.
+        let mut targets = BTreeMap::new();
+        let mut found = Vec::new();
+        module.annotations.retain(|inst| {
+            match (inst.class.opcode, inst.operands.as_slice()) {
+                (
+                    Op::Decorate,
+                    [Operand::IdRef(target), Operand::Decoration(Decoration::SpecId), Operand::LiteralBit32(spec_id)],
+                ) if self.constants.contains_key(spec_id) => {
+                    targets.insert(*target, self.constants[spec_id]);
+                    found.push(*spec_id);
+                    false
+                }
+                _ => true,
+            }
+        });
+
+        for spec_id in self.constants.keys() {
+            if !found.contains(spec_id) {
+                log::warn!("Module has no specialization constant with SpecId {spec_id}");
+            }
+        }
+
+        for inst in module.types_global_values.iter_mut() {
+            let value = match inst.result_id.and_then(|id| targets.get(&id)) {
+                Some(value) => *value,
+                None => continue,
+            };
+
+            *inst = match inst.class.opcode {
+                Op::SpecConstantTrue | Op::SpecConstantFalse => dr::Instruction::new(
+                    if value != 0 {
+                        Op::ConstantTrue
+                    } else {
+                        Op::ConstantFalse
+                    },
+                    inst.result_type,
+                    inst.result_id,
+                    Vec::new(),
+                ),
+                Op::SpecConstant => match inst.operands.as_slice() {
+                    [Operand::LiteralBit32(_)] => dr::Instruction::new(
+                        Op::Constant,
+                        inst.result_type,
+                        inst.result_id,
+                        vec![Operand::LiteralBit32(value)],
+                    ),
+                    _ => {
+                        return Err(format!(
+                            "specialization constant %{} is not 32 bit wide",
+                            inst.result_id.unwrap_or_default()
+                        ))
+                    }
+                },
+                op => return Err(format!("can not specialize {op:?}")),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl PatchStage for Specialize {
+    fn name(&self) -> &str {
+        "specialization"
+    }
+
+    fn cache_id(&self) -> String {
+        format!("{}{:?}", self.name(), self.constants)
+    }
+
+    fn run(&self, _ctx: &PatchContext, state: &mut PatchState) -> Result<(), PatchError> {
+        let mut module = load_patched(self.name(), &state.code)?;
+        self.apply(&mut module).map_err(|error| PatchError::Stage {
+            stage: self.name().to_owned(),
+            error,
+        })?;
+        state.code = module.assemble();
+        Ok(())
+    }
+}
+
+///Validates the patched module, see [validate](crate::validate).
+pub struct Validate;
+
+impl PatchStage for Validate {
+    fn name(&self) -> &str {
+        "validation"
+    }
+
+    fn run(&self, _ctx: &PatchContext, state: &mut PatchState) -> Result<(), PatchError> {
+        validate(&state.code, ENTRY_POINT)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specializes_constants() {
+        let mut b = dr::Builder::new();
+        let uint = b.type_int(32, 0);
+        let bool_type = b.type_bool();
+        let steps = b.spec_constant_bit32(uint, 64);
+        let shadows = b.spec_constant_true(bool_type);
+        let untouched = b.spec_constant_bit32(uint, 1);
+        b.decorate(steps, Decoration::SpecId, vec![Operand::LiteralBit32(0)]);
+        b.decorate(shadows, Decoration::SpecId, vec![Operand::LiteralBit32(1)]);
+        b.decorate(
+            untouched,
+            Decoration::SpecId,
+            vec![Operand::LiteralBit32(2)],
+        );
+        let mut module = b.module();
+
+        Specialize::default()
+            .with(0, 128)
+            .with(1, 0)
+            .apply(&mut module)
+            .unwrap();
+
+        let find = |id| {
+            module
+                .types_global_values
+                .iter()
+                .find(|inst| inst.result_id == Some(id))
+                .unwrap()
+        };
+        assert_eq!(find(steps).class.opcode, Op::Constant);
+        assert_eq!(find(steps).operands, vec![Operand::LiteralBit32(128)]);
+        assert_eq!(find(shadows).class.opcode, Op::ConstantFalse);
+        assert_eq!(find(untouched).class.opcode, Op::SpecConstant);
+        assert_eq!(module.annotations.len(), 1);
+    }
+}
@@ -0,0 +1,315 @@
+//! Structural validation of patched modules, before they are handed to a driver.
+
+use ahash::AHashSet;
+use patch_function::rspirv::{
+    dr::{self, Operand},
+    spirv::{self, Op, Word},
+};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Error)]
+pub enum ValidationError {
+    #[error("Could not parse module: {0}")]
+    Parse(String),
+    #[error("Module has no header")]
+    MissingHeader,
+    #[error("Module declares id bound {bound}, but uses id {id}")]
+    IdOutOfBound { id: Word, bound: Word },
+    #[error("Id {id} is defined more than once")]
+    DuplicateId { id: Word },
+    #[error("{op:?} (result {result:?}) references undefined id {id}")]
+    UndefinedId {
+        op: Op,
+        result: Option<Word>,
+        id: Word,
+    },
+    #[error("{op:?} (result {result:?}) uses {type_id} as result type, which is not a type")]
+    NotAType {
+        op: Op,
+        result: Option<Word>,
+        type_id: Word,
+    },
+    #[error("Module has no memory model, or not the expected {expected:?} memory model")]
+    MemoryModel { expected: spirv::MemoryModel },
+    #[error("Entry point {name} not found")]
+    MissingEntryPoint { name: String },
+    #[error("Entry point {name} has execution model {found:?}, expected {expected:?}")]
+    ExecutionModel {
+        name: String,
+        found: spirv::ExecutionModel,
+        expected: spirv::ExecutionModel,
+    },
+    #[error("Entry point {name} does not point to a function")]
+    EntryPointFunction { name: String },
+    #[error("Interface id {id} of entry point {name} is not a global variable")]
+    Interface { name: String, id: Word },
+}
+
+///Validates `code` for structure, types and the compute entry point `entry_point`.
+pub fn validate(code: &[u32], entry_point: &str) -> Result<(), ValidationError> {
+    let module = dr::load_words(code).map_err(|e| ValidationError::Parse(e.to_string()))?;
+    let header = module
+        .header
+        .as_ref()
+        .ok_or(ValidationError::MissingHeader)?;
+
+    let mut defined = AHashSet::default();
+    for inst in module.all_inst_iter() {
+        if let Some(id) = inst.result_id {
+            if id >= header.bound {
+                return Err(ValidationError::IdOutOfBound {
+                    id,
+                    bound: header.bound,
+                });
+            }
+            if !defined.insert(id) {
+                return Err(ValidationError::DuplicateId { id });
+            }
+        }
+    }
+
+    let types = module
+        .types_global_values
+        .iter()
+        .filter(|inst| is_type(inst.class.opcode))
+        .filter_map(|inst| inst.result_id)
+        .collect::<AHashSet<_>>();
+
+    //NOTE: Debug names are ignored, since they are allowed to outlive functions that got patched out.
+    for inst in module
+        .all_inst_iter()
+        .filter(|inst| !matches!(inst.class.opcode, Op::Name | Op::MemberName))
+    {
+        if let Some(type_id) = inst.result_type {
+            if !types.contains(&type_id) {
+                return Err(ValidationError::NotAType {
+                    op: inst.class.opcode,
+                    result: inst.result_id,
+                    type_id,
+                });
+            }
+        }
+
+        for op in &inst.operands {
+            if let Operand::IdRef(id) = op {
+                if !defined.contains(id) {
+                    return Err(ValidationError::UndefinedId {
+                        op: inst.class.opcode,
+                        result: inst.result_id,
+                        id: *id,
+                    });
+                }
+            }
+        }
+    }
+
+    let expected_memory_model = spirv::MemoryModel::Vulkan;
+    match module
+        .memory_model
+        .as_ref()
+        .map(|mm| mm.operands.as_slice())
+    {
+        Some([_, Operand::MemoryModel(mm)]) if *mm == expected_memory_model => {}
+        _ => {
+            return Err(ValidationError::MemoryModel {
+                expected: expected_memory_model,
+            })
+        }
+    }
+
+    validate_entry_point(&module, entry_point)
+}
+
+///True if `code` has a valid compute entry point `entry_point`. Does not validate the rest of the module.
+pub fn has_entry_point(code: &[u32], entry_point: &str) -> bool {
+    match dr::load_words(code) {
+        Ok(module) => validate_entry_point(&module, entry_point).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn validate_entry_point(module: &dr::Module, name: &str) -> Result<(), ValidationError> {
+    let entry = module
+        .entry_points
+        .iter()
+        .find(|ep| matches!(ep.operands.get(2), Some(Operand::LiteralString(n)) if n == name))
+        .ok_or_else(|| ValidationError::MissingEntryPoint {
+            name: name.to_owned(),
+        })?;
+
+    match entry.operands.first() {
+        Some(Operand::ExecutionModel(spirv::ExecutionModel::GLCompute)) => {}
+        Some(Operand::ExecutionModel(found)) => {
+            return Err(ValidationError::ExecutionModel {
+                name: name.to_owned(),
+                found: *found,
+                expected: spirv::ExecutionModel::GLCompute,
+            })
+        }
+        _ => {
+            return Err(ValidationError::MissingEntryPoint {
+                name: name.to_owned(),
+            })
+        }
+    }
+
+    let function_exists = match entry.operands.get(1) {
+        Some(Operand::IdRef(id)) => module.functions.iter().any(|f| f.def_id() == Some(*id)),
+        _ => false,
+    };
+    if !function_exists {
+        return Err(ValidationError::EntryPointFunction {
+            name: name.to_owned(),
+        });
+    }
+
+    let globals = module
+        .types_global_values
+        .iter()
+        .filter(|inst| inst.class.opcode == Op::Variable)
+        .filter_map(|inst| inst.result_id)
+        .collect::<AHashSet<_>>();
+    for op in entry.operands.iter().skip(3) {
+        if let Operand::IdRef(id) = op {
+            if !globals.contains(id) {
+                return Err(ValidationError::Interface {
+                    name: name.to_owned(),
+                    id: *id,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_type(op: Op) -> bool {
+    matches!(
+        op,
+        Op::TypeVoid
+            | Op::TypeBool
+            | Op::TypeInt
+            | Op::TypeFloat
+            | Op::TypeVector
+            | Op::TypeMatrix
+            | Op::TypeImage
+            | Op::TypeSampler
+            | Op::TypeSampledImage
+            | Op::TypeArray
+            | Op::TypeRuntimeArray
+            | Op::TypeStruct
+            | Op::TypeOpaque
+            | Op::TypePointer
+            | Op::TypeFunction
+            | Op::TypeEvent
+            | Op::TypeDeviceEvent
+            | Op::TypeReserveId
+            | Op::TypeQueue
+            | Op::TypePipe
+            | Op::TypeAccelerationStructureKHR
+            | Op::TypeRayQueryKHR
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use patch_function::rspirv::{
+        binary::Assemble,
+        spirv::{AddressingModel, Capability, ExecutionModel, FunctionControl, StorageClass},
+    };
+
+    ///Module with an empty compute entry point `renderer`. `extend` can add instructions before the function.
+    fn module(
+        memory_model: spirv::MemoryModel,
+        execution_model: ExecutionModel,
+        extend: impl FnOnce(&mut dr::Builder),
+    ) -> Vec<u32> {
+        let mut b = dr::Builder::new();
+        b.capability(Capability::Shader);
+        b.capability(Capability::VulkanMemoryModel);
+        b.memory_model(AddressingModel::Logical, memory_model);
+        extend(&mut b);
+
+        let void = b.type_void();
+        let function_type = b.type_function(void, vec![]);
+        let function = b
+            .begin_function(void, None, FunctionControl::NONE, function_type)
+            .unwrap();
+        b.begin_block(None).unwrap();
+        b.ret().unwrap();
+        b.end_function().unwrap();
+        b.entry_point(execution_model, function, "renderer", vec![]);
+        b.module().assemble()
+    }
+
+    fn compute_module(extend: impl FnOnce(&mut dr::Builder)) -> Vec<u32> {
+        module(
+            spirv::MemoryModel::Vulkan,
+            ExecutionModel::GLCompute,
+            extend,
+        )
+    }
+
+    #[test]
+    fn accepts_valid_module() {
+        validate(&compute_module(|_| {}), "renderer").unwrap();
+    }
+
+    #[test]
+    fn rejects_undefined_id() {
+        let code = compute_module(|b| {
+            b.type_pointer(None, StorageClass::Function, 999);
+        });
+        assert!(matches!(
+            validate(&code, "renderer"),
+            Err(ValidationError::UndefinedId { id: 999, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_non_type_result_type() {
+        let mut constant = 0;
+        let code = compute_module(|b| {
+            let bool_type = b.type_bool();
+            constant = b.constant_true(bool_type);
+            b.constant_true(constant);
+        });
+        assert!(matches!(
+            validate(&code, "renderer"),
+            Err(ValidationError::NotAType { type_id, .. }) if type_id == constant
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_memory_model() {
+        let code = module(
+            spirv::MemoryModel::GLSL450,
+            ExecutionModel::GLCompute,
+            |_| {},
+        );
+        assert!(matches!(
+            validate(&code, "renderer"),
+            Err(ValidationError::MemoryModel { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_or_non_compute_entry_point() {
+        assert!(matches!(
+            validate(&compute_module(|_| {}), "other"),
+            Err(ValidationError::MissingEntryPoint { .. })
+        ));
+        assert!(!has_entry_point(&compute_module(|_| {}), "other"));
+
+        let fragment = module(spirv::MemoryModel::Vulkan, ExecutionModel::Fragment, |_| {});
+        assert!(matches!(
+            validate(&fragment, "renderer"),
+            Err(ValidationError::ExecutionModel {
+                found: ExecutionModel::Fragment,
+                ..
+            })
+        ));
+        assert!(!has_entry_point(&fragment, "renderer"));
+    }
+}
@@ -1,109 +1,112 @@
 use std::{
-    fs::create_dir_all,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
     path::{Path, PathBuf},
 };
 
-use spirv_builder::{
-    Capability, MetadataPrintout, ModuleResult, SpirvBuilder, SpirvBuilderError, SpirvMetadata,
-};
-
-///Builds the shader crate and moves all files to a location that can be found by the renderer's loader.
-pub fn compile_rust_shader(
-    output_name: &str,
-    shader_crate: &str,
-    destination_folder: &str,
-) -> Result<(), SpirvBuilderError> {
-    let shader_crate_location = Path::new(shader_crate).canonicalize().unwrap();
-    if !shader_crate_location.exists() {
-        println!("cargo:warning=no crate at: {shader_crate_location:?}");
-        return Err(SpirvBuilderError::CratePathDoesntExist(
-            shader_crate_location,
-        ));
-    }
+use builder::ShaderBuild;
+use variants::VARIANTS;
 
-    println!("cargo:warning=Building shader {shader_crate_location:?}");
+//NOTE: The build script uses the same sources as the library, but not all of the API.
+#[allow(dead_code)]
+#[path = "src/builder.rs"]
+mod builder;
+#[allow(dead_code)]
+#[path = "src/reflect.rs"]
+mod reflect;
+#[allow(dead_code)]
+#[path = "src/variants.rs"]
+mod variants;
 
-    let spirv_target_location = Path::new(destination_folder).canonicalize().unwrap();
-
-    if !spirv_target_location.exists() {
-        create_dir_all(&spirv_target_location).expect("Could not create spirv directory!");
-    }
+///Directory all generated shaders are written to, relative to this crate. Contains nothing else, so it is
+/// never cleaned up, outputs are just overwritten.
+const OUTPUT_DIR: &str = "../../resources/generated";
 
-    let compiler_result = SpirvBuilder::new(&shader_crate_location, "spirv-unknown-vulkan1.2")
-        .spirv_metadata(SpirvMetadata::Full)
-        .print_metadata(MetadataPrintout::None)
-        .capability(Capability::Int8)
-        .capability(Capability::Int16)
-        .capability(Capability::ImageQuery)
-        .capability(Capability::RuntimeDescriptorArray)
-        //.capability(Capability::GroupNonUniform)
-        //.capability(Capability::InputAttachment)
-        //.capability(Capability::InputAttachmentArrayDynamicIndexing)
-        //.capability(Capability::InputAttachmentArrayNonUniformIndexing)
-        .capability(Capability::RuntimeDescriptorArray)
-        .capability(Capability::SampledImageArrayDynamicIndexing)
-        .capability(Capability::SampledImageArrayNonUniformIndexing)
-        .capability(Capability::ShaderNonUniform)
-        .capability(Capability::StorageBufferArrayDynamicIndexing)
-        .capability(Capability::StorageBufferArrayNonUniformIndexing)
-        .capability(Capability::StorageImageArrayDynamicIndexing)
-        .capability(Capability::StorageImageArrayNonUniformIndexing)
-        .capability(Capability::StorageImageReadWithoutFormat)
-        .capability(Capability::StorageImageWriteWithoutFormat)
-        //.capability(Capability::UniformBufferArrayDynamicIndexing)
-        //.capability(Capability::UniformBufferArrayNonUniformIndexing)
-        .capability(Capability::VulkanMemoryModel)
-        .build()?;
+///Crates the base shader is built from. Changing any of them rebuilds the shader.
+const SHADER_CRATES: &[&str] = &["../base-shader", "../shared"];
 
-    println!("cargo:warning=Generated following Spirv entrypoints:");
-    for e in &compiler_result.entry_points {
-        println!("cargo:warning=    {e}");
-    }
-    let move_spirv_file = |spv_location: &Path, entry: Option<String>| {
-        let mut target = spirv_target_location.clone();
-        if let Some(e) = entry {
-            target = target.join(format!("{output_name}_{e}.spv"));
-        } else {
-            target = target.join(format!("{output_name}.spv"));
+///Collects all `.rs` and `.toml` files below `dir`, skipping `target` directories.
+fn collect_sources(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("cargo:warning=Could not read {dir:?}: {e}");
+            return;
         }
-
-        println!("cargo:warning=Copying {spv_location:?} to {target:?}");
-        std::fs::copy(spv_location, &target).expect("Failed to copy spirv file!");
     };
 
-    match compiler_result.module {
-        ModuleResult::MultiModule(modules) => {
-            //Note currently ignoring entry name since all of them should be "main", just copying the
-            //shader files. Later might use a more sophisticated approach.
-            for (entry, src_file) in modules {
-                move_spirv_file(&src_file, Some(entry));
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if path.file_name().map(|n| n != "target").unwrap_or(false) {
+                collect_sources(&path, files);
             }
+        } else if path
+            .extension()
+            .map(|ext| ext == "rs" || ext == "toml")
+            .unwrap_or(false)
+        {
+            files.push(path);
         }
-        ModuleResult::SingleModule(path) => {
-            move_spirv_file(&path, None);
-        }
-    };
-    Ok(())
+    }
 }
 
-const RESDIR: &str = "../resources";
-
-fn clean_up() {
-    let path = PathBuf::from(RESDIR);
-    if path.exists() {
-        std::fs::remove_dir_all(&path).unwrap();
+///Hash over the paths and content of all `files`.
+fn source_hash(files: &[PathBuf]) -> String {
+    let mut hasher = DefaultHasher::new();
+    for file in files {
+        file.hash(&mut hasher);
+        std::fs::read(file).unwrap_or_default().hash(&mut hasher);
     }
-
-    std::fs::create_dir(&path).unwrap();
+    format!("{:016x}", hasher.finish())
 }
 
 // Builds rust shader crate and all glsl shaders.
 fn main() {
-    println!("cargo:rerun-if-changed=../base_shader/src/lib.rs");
+    let mut sources = vec![
+        PathBuf::from("build.rs"),
+        PathBuf::from("src/builder.rs"),
+        PathBuf::from("src/reflect.rs"),
+        PathBuf::from("src/variants.rs"),
+        PathBuf::from("../../Cargo.lock"),
+    ];
+    for file in &sources {
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+    for shader_crate in SHADER_CRATES {
+        //NOTE: Directories are scanned recursively by cargo, so new files are noticed as well.
+        println!("cargo:rerun-if-changed={shader_crate}/src");
+        println!("cargo:rerun-if-changed={shader_crate}/Cargo.toml");
+        collect_sources(Path::new(shader_crate), &mut sources);
+    }
+    sources.sort();
 
-    //cleanup resource dir
-    clean_up();
+    let hash = source_hash(&sources);
+    for variant in VARIANTS {
+        //Skip the build if neither the sources nor the variant changed since the last one.
+        let variant_hash = format!("{hash} {variant:?}");
+        let output_name = variant.output_name();
+        let hash_file = Path::new(OUTPUT_DIR).join(format!("{output_name}.hash"));
+        let output = Path::new(OUTPUT_DIR).join(format!("{output_name}.spv"));
+        if output.exists()
+            && std::fs::read_to_string(&hash_file).ok().as_deref() == Some(variant_hash.as_str())
+        {
+            continue;
+        }
 
-    //build shader crate. generates a module per entry point
-    compile_rust_shader("base-shader", "../base-shader/", RESDIR).unwrap();
+        println!(
+            "cargo:warning=Building shader crate ../base-shader/ variant {}",
+            variant.name
+        );
+        let modules = ShaderBuild::new("../base-shader/", output_name)
+            .with_default_features(variant.default_features)
+            .with_features(variant.features.iter().map(|f| f.to_string()).collect())
+            .with_layout(true)
+            .build_into(Path::new(OUTPUT_DIR))
+            .unwrap();
+        for module in modules {
+            println!("cargo:warning=Generated {:?}", module.path);
+        }
+        std::fs::write(&hash_file, variant_hash).expect("Could not write shader hash!");
+    }
 }
@@ -0,0 +1,217 @@
+//! Configurable build of a rust-gpu shader crate.
+
+use std::path::{Path, PathBuf};
+
+use spirv_builder::{
+    Capability, MetadataPrintout, ModuleResult, SpirvBuilder, SpirvBuilderError, SpirvMetadata,
+};
+use thiserror::Error;
+
+use crate::reflect::PushConstantLayout;
+
+///Capabilities the renderer's shaders are built with.
+pub const DEFAULT_CAPABILITIES: &[Capability] = &[
+    Capability::Int8,
+    Capability::Int16,
+    Capability::ImageQuery,
+    Capability::RuntimeDescriptorArray,
+    Capability::SampledImageArrayDynamicIndexing,
+    Capability::SampledImageArrayNonUniformIndexing,
+    Capability::ShaderNonUniform,
+    Capability::StorageBufferArrayDynamicIndexing,
+    Capability::StorageBufferArrayNonUniformIndexing,
+    Capability::StorageImageArrayDynamicIndexing,
+    Capability::StorageImageArrayNonUniformIndexing,
+    Capability::StorageImageReadWithoutFormat,
+    Capability::StorageImageWriteWithoutFormat,
+    Capability::VulkanMemoryModel,
+];
+
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("No shader crate at {0:?}")]
+    CrateNotFound(PathBuf),
+    #[error("Failed to compile shader crate: {0}")]
+    Compile(#[source] SpirvBuilderError),
+    #[error("Could not write {path:?}: {error}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+}
+
+///A module generated by [ShaderBuild::build].
+#[derive(Debug, Clone)]
+pub struct BuiltModule {
+    ///Entry point the module was generated for. `None` for single module builds, which contain all entry points.
+    pub entry_point: Option<String>,
+    pub path: PathBuf,
+}
+
+///Configuration of a shader crate build.
+#[derive(Debug, Clone)]
+pub struct ShaderBuild {
+    shader_crate: PathBuf,
+    output_name: String,
+    target: String,
+    capabilities: Vec<Capability>,
+    multimodule: bool,
+    entry_points: Vec<String>,
+    emit_layout: bool,
+    features: Vec<String>,
+    default_features: bool,
+}
+
+impl ShaderBuild {
+    ///Builds `shader_crate` for Vulkan 1.2 with [DEFAULT_CAPABILITIES] into a single module. Outputs are named
+    /// `<output_name>.spv`.
+    pub fn new(shader_crate: impl Into<PathBuf>, output_name: impl Into<String>) -> Self {
+        ShaderBuild {
+            shader_crate: shader_crate.into(),
+            output_name: output_name.into(),
+            target: "spirv-unknown-vulkan1.2".to_owned(),
+            capabilities: DEFAULT_CAPABILITIES.to_vec(),
+            multimodule: false,
+            entry_points: Vec::new(),
+            emit_layout: false,
+            features: Vec::new(),
+            default_features: true,
+        }
+    }
+
+    ///Sets the target, like `spirv-unknown-vulkan1.2`.
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    ///Replaces all capabilities.
+    pub fn with_capabilities(mut self, capabilities: Vec<Capability>) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn with_capability(mut self, capability: Capability) -> Self {
+        self.capabilities.push(capability);
+        self
+    }
+
+    ///Generates a module per entry point, named `<output_name>_<entry point>.spv`.
+    pub fn with_multimodule(mut self, multimodule: bool) -> Self {
+        self.multimodule = multimodule;
+        self
+    }
+
+    ///Only keeps the modules of the given entry points in multi module builds. All modules are kept if empty.
+    pub fn with_entry_points(mut self, entry_points: Vec<String>) -> Self {
+        self.entry_points = entry_points;
+        self
+    }
+
+    ///Enables the given cargo features of the shader crate.
+    pub fn with_features(mut self, features: Vec<String>) -> Self {
+        self.features = features;
+        self
+    }
+
+    ///Enables or disables the default features of the shader crate. Enabled by default.
+    pub fn with_default_features(mut self, default_features: bool) -> Self {
+        self.default_features = default_features;
+        self
+    }
+
+    ///Writes the push constant layout next to every module copied by [Self::build_into], see
+    /// [PushConstantLayout].
+    pub fn with_layout(mut self, emit_layout: bool) -> Self {
+        self.emit_layout = emit_layout;
+        self
+    }
+
+    ///Compiles the crate. The modules are left in spirv-builder's target directory.
+    pub fn build(&self) -> Result<Vec<BuiltModule>, BuildError> {
+        let shader_crate = self
+            .shader_crate
+            .canonicalize()
+            .map_err(|_| BuildError::CrateNotFound(self.shader_crate.clone()))?;
+
+        let mut builder = SpirvBuilder::new(&shader_crate, &self.target)
+            .spirv_metadata(SpirvMetadata::Full)
+            .print_metadata(MetadataPrintout::None)
+            .multimodule(self.multimodule)
+            .shader_crate_default_features(self.default_features)
+            .shader_crate_features(self.features.iter().cloned());
+        for capability in &self.capabilities {
+            builder = builder.capability(*capability);
+        }
+        let result = builder.build().map_err(BuildError::Compile)?;
+
+        let modules = match result.module {
+            ModuleResult::MultiModule(modules) => modules
+                .into_iter()
+                .filter(|(entry, _)| {
+                    self.entry_points.is_empty() || self.entry_points.contains(entry)
+                })
+                .map(|(entry, path)| BuiltModule {
+                    entry_point: Some(entry),
+                    path,
+                })
+                .collect(),
+            ModuleResult::SingleModule(path) => vec![BuiltModule {
+                entry_point: None,
+                path,
+            }],
+        };
+        Ok(modules)
+    }
+
+    ///Compiles the crate and copies all modules to `destination`. Returns the copied modules.
+    pub fn build_into(&self, destination: &Path) -> Result<Vec<BuiltModule>, BuildError> {
+        std::fs::create_dir_all(destination).map_err(|error| BuildError::Io {
+            path: destination.to_path_buf(),
+            error,
+        })?;
+
+        let mut copied = Vec::new();
+        for module in self.build()? {
+            let file_name = match &module.entry_point {
+                Some(entry) => format!("{}_{entry}.spv", self.output_name),
+                None => format!("{}.spv", self.output_name),
+            };
+            let target = destination.join(file_name);
+            std::fs::copy(&module.path, &target).map_err(|error| BuildError::Io {
+                path: target.clone(),
+                error,
+            })?;
+
+            if self.emit_layout {
+                write_layout(&target)?;
+            }
+            copied.push(BuiltModule {
+                entry_point: module.entry_point,
+                path: target,
+            });
+        }
+        Ok(copied)
+    }
+}
+
+///Writes the push constant layout of the module at `spv` to a `.layout` file next to it.
+fn write_layout(spv: &Path) -> Result<(), BuildError> {
+    let bytes = std::fs::read(spv).map_err(|error| BuildError::Io {
+        path: spv.to_path_buf(),
+        error,
+    })?;
+    let words = bytes
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect::<Vec<_>>();
+
+    //NOTE: Modules that can't be reflected just don't get a layout file, the check at runtime reports that.
+    if let Ok(Some(layout)) = PushConstantLayout::reflect(&words) {
+        let path = spv.with_extension("layout");
+        std::fs::write(&path, layout.to_string())
+            .map_err(|error| BuildError::Io { path, error })?;
+    }
+    Ok(())
+}
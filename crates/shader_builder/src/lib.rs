@@ -1,14 +1,14 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
+//! Builds the renderer's shader crates. The build script of this crate compiles `base-shader`, the library exposes
+//! the same tools, so shader crates can be built at runtime, or by other crates, as well.
+//!
+//! Building needs the `compile` feature, which pulls in spirv-builder.
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+#[cfg(feature = "compile")]
+pub mod builder;
+pub mod reflect;
+pub mod variants;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+#[cfg(feature = "compile")]
+pub use builder::{BuildError, BuiltModule, ShaderBuild, DEFAULT_CAPABILITIES};
+#[cfg(feature = "compile")]
+pub use spirv_builder::Capability;
@@ -0,0 +1,198 @@
+//! Reflection of the push constant block of a SPIR-V module.
+//!
+//! Used by the build script to emit the layout next to the shader, and at runtime to check host side structs
+//! against it.
+
+use std::fmt::Display;
+
+use rspirv::{
+    dr::{self, Operand},
+    spirv::{Decoration, Op, StorageClass, Word},
+};
+
+///A member of a push constant block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockMember {
+    pub name: Option<String>,
+    pub offset: u32,
+    pub size: u32,
+}
+
+///Layout of a push constant block, as generated by the shader compiler.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PushConstantLayout {
+    pub members: Vec<BlockMember>,
+}
+
+impl Display for PushConstantLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, member) in self.members.iter().enumerate() {
+            writeln!(
+                f,
+                "{i} {} offset {} size {}",
+                member.name.as_deref().unwrap_or("?"),
+                member.offset,
+                member.size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl PushConstantLayout {
+    ///Reflects the push constant block of `code`. Returns `None` if the module has no push constants.
+    pub fn reflect(code: &[u32]) -> Result<Option<Self>, String> {
+        let module = dr::load_words(code).map_err(|e| e.to_string())?;
+        let pointer = module.types_global_values.iter().find_map(|inst| {
+            match (inst.class.opcode, inst.operands.as_slice()) {
+                (Op::Variable, [Operand::StorageClass(StorageClass::PushConstant), ..]) => {
+                    inst.result_type
+                }
+                _ => None,
+            }
+        });
+        let pointer = match pointer {
+            Some(pointer) => pointer,
+            None => return Ok(None),
+        };
+
+        let block = match find(&module, pointer).map(|i| (i.class.opcode, i.operands.as_slice())) {
+            Some((Op::TypePointer, [_, Operand::IdRef(pointee)])) => *pointee,
+            _ => return Err("push constant variable has no pointer type".to_owned()),
+        };
+        let block_type = find(&module, block)
+            .filter(|inst| inst.class.opcode == Op::TypeStruct)
+            .ok_or_else(|| "push constant block is not a struct".to_owned())?;
+
+        let members = block_type
+            .operands
+            .iter()
+            .enumerate()
+            .map(|(i, op)| {
+                let member = i as u32;
+                let member_type = match op {
+                    Operand::IdRef(id) => *id,
+                    _ => return Err(format!("member {i} has no type")),
+                };
+                Ok(BlockMember {
+                    name: member_name(&module, block, member),
+                    offset: member_offset(&module, block, member)
+                        .ok_or_else(|| format!("member {i} has no offset"))?,
+                    size: type_size(&module, member_type)?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Some(PushConstantLayout { members }))
+    }
+
+    ///Compares the layout against the host side `fields` (name, byte offset and size, in declaration order).
+    /// Describes every difference on its own line.
+    pub fn check(&self, fields: &[(&str, usize, usize)]) -> Result<(), String> {
+        let mut errors = Vec::new();
+        if self.members.len() != fields.len() {
+            errors.push(format!(
+                "shader block has {} members, host struct has {} fields",
+                self.members.len(),
+                fields.len()
+            ));
+        }
+
+        for (member, (name, offset, size)) in self.members.iter().zip(fields) {
+            if member.offset as usize != *offset || member.size as usize != *size {
+                errors.push(format!(
+                    "{name}: shader offset {} size {}, host offset {offset} size {size}",
+                    member.offset, member.size
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+fn find(module: &dr::Module, id: Word) -> Option<&dr::Instruction> {
+    module
+        .types_global_values
+        .iter()
+        .find(|inst| inst.result_id == Some(id))
+}
+
+fn member_name(module: &dr::Module, structure: Word, member: u32) -> Option<String> {
+    module
+        .debug_names
+        .iter()
+        .find_map(|inst| match (inst.class.opcode, inst.operands.as_slice()) {
+            (
+                Op::MemberName,
+                [Operand::IdRef(s), Operand::LiteralBit32(m), Operand::LiteralString(name)],
+            ) if *s == structure && *m == member => Some(name.clone()),
+            _ => None,
+        })
+}
+
+fn member_offset(module: &dr::Module, structure: Word, member: u32) -> Option<u32> {
+    module
+        .annotations
+        .iter()
+        .find_map(|inst| match (inst.class.opcode, inst.operands.as_slice()) {
+            (
+                Op::MemberDecorate,
+                [Operand::IdRef(s), Operand::LiteralBit32(m), Operand::Decoration(Decoration::Offset), Operand::LiteralBit32(offset)],
+            ) if *s == structure && *m == member => Some(*offset),
+            _ => None,
+        })
+}
+
+fn array_stride(module: &dr::Module, array: Word) -> Option<u32> {
+    module
+        .annotations
+        .iter()
+        .find_map(|inst| match (inst.class.opcode, inst.operands.as_slice()) {
+            (
+                Op::Decorate,
+                [Operand::IdRef(target), Operand::Decoration(Decoration::ArrayStride), Operand::LiteralBit32(stride)],
+            ) if *target == array => Some(*stride),
+            _ => None,
+        })
+}
+
+///Size in bytes of the type `id`, as laid out in a push constant block.
+fn type_size(module: &dr::Module, id: Word) -> Result<u32, String> {
+    let inst = find(module, id).ok_or_else(|| format!("type %{id} is undefined"))?;
+    match (inst.class.opcode, inst.operands.as_slice()) {
+        (Op::TypeInt | Op::TypeFloat, [Operand::LiteralBit32(width), ..]) => Ok(width / 8),
+        (
+            Op::TypeVector | Op::TypeMatrix,
+            [Operand::IdRef(component), Operand::LiteralBit32(count)],
+        ) => Ok(type_size(module, *component)? * count),
+        (Op::TypeArray, [Operand::IdRef(element), Operand::IdRef(length)]) => {
+            let length = match find(module, *length).map(|i| i.operands.as_slice()) {
+                Some([Operand::LiteralBit32(length)]) => *length,
+                _ => return Err(format!("array %{id} has no constant length")),
+            };
+            let stride = match array_stride(module, id) {
+                Some(stride) => stride,
+                None => type_size(module, *element)?,
+            };
+            Ok(stride * length)
+        }
+        (Op::TypeStruct, members) => {
+            let mut size = 0;
+            for (i, member) in members.iter().enumerate() {
+                let member_type = match member {
+                    Operand::IdRef(id) => *id,
+                    _ => return Err(format!("member {i} of struct %{id} has no type")),
+                };
+                let offset = member_offset(module, id, i as u32).unwrap_or(size);
+                size = size.max(offset + type_size(module, member_type)?);
+            }
+            Ok(size)
+        }
+        (op, _) => Err(format!("can not compute the size of {op:?}")),
+    }
+}
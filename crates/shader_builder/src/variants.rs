@@ -0,0 +1,49 @@
+//! Builds of the base shader with different cargo features.
+
+///A build of the base shader with a set of cargo features.
+#[derive(Debug)]
+pub struct Variant {
+    pub name: &'static str,
+    pub default_features: bool,
+    pub features: &'static [&'static str],
+}
+
+impl Variant {
+    ///`base-shader` for the default variant, `base-shader-<name>` for all others.
+    pub fn output_name(&self) -> String {
+        if self.name == "default" {
+            "base-shader".to_owned()
+        } else {
+            format!("base-shader-{}", self.name)
+        }
+    }
+
+    ///The variant called `name`.
+    pub fn find(name: &str) -> Option<&'static Variant> {
+        VARIANTS.iter().find(|v| v.name == name)
+    }
+}
+
+//NOTE: Keep in sync with `sdf_patcher::BASE_SHADER_VARIANTS`, its tests compare both lists.
+pub const VARIANTS: &[Variant] = &[
+    Variant {
+        name: "default",
+        default_features: true,
+        features: &[],
+    },
+    Variant {
+        name: "fast",
+        default_features: false,
+        features: &[],
+    },
+    Variant {
+        name: "high_quality",
+        default_features: true,
+        features: &["high_quality"],
+    },
+    Variant {
+        name: "debug_renderer",
+        default_features: true,
+        features: &["debug_renderer"],
+    },
+];
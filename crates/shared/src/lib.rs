@@ -12,6 +12,53 @@ use spirv_std::glam::{Quat, Vec2, Vec3};
 #[cfg(target_arch = "spirv")]
 use spirv_std::num_traits::Float;
 
+///What the renderer writes to the target image.
+pub mod render_mode {
+    ///The shaded scene.
+    pub const SHADED: u32 = 0;
+    ///Surface normals, mapped to 0..1.
+    pub const NORMALS: u32 = 1;
+    ///Linear depth along the ray, relative to the ray's maximum distance.
+    pub const DEPTH: u32 = 2;
+    ///Heatmap of the number of sphere tracing steps.
+    pub const STEPS: u32 = 3;
+    ///Distance to the surface at which tracing stopped, relative to the surface epsilon.
+    pub const DISTANCE_ERROR: u32 = 4;
+    ///Hits in green, misses in blue and rays that exceeded the step budget in red.
+    pub const HIT_CLASS: u32 = 5;
+
+    ///Number of render modes.
+    pub const COUNT: u32 = 6;
+
+    #[cfg(not(target_arch = "spirv"))]
+    pub fn name(mode: u32) -> &'static str {
+        match mode {
+            SHADED => "shaded",
+            NORMALS => "normals",
+            DEPTH => "depth",
+            STEPS => "steps",
+            DISTANCE_ERROR => "distance error",
+            HIT_CLASS => "hit class",
+            _ => "unknown",
+        }
+    }
+}
+
+///Bits of [RenderUniform::flags].
+pub mod flags {
+    ///Scales the surface epsilon with the footprint of a pixel at the traced distance.
+    pub const PIXEL_EPSILON: u32 = 1 << 0;
+    ///Over-relaxed sphere tracing, scaling each step by [RenderUniform::relaxation](super::RenderUniform::relaxation).
+    pub const RELAXED: u32 = 1 << 1;
+    ///Starts each ray at the distance of its tile in [RenderUniform::start_image](super::RenderUniform::start_image).
+    pub const CONE_PREPASS: u32 = 1 << 2;
+    ///Traces soft shadows toward the light, see [RenderUniform::shadow_softness](super::RenderUniform::shadow_softness).
+    pub const SOFT_SHADOWS: u32 = 1 << 3;
+}
+
+///Width and height in pixels of the tiles the cone pre-pass traces.
+pub const CONE_TILE_SIZE: u32 = 8;
+
 #[cfg_attr(not(target_arch = "spirv"), derive(Clone, Copy, Debug))]
 #[cfg_attr(target_arch = "spirv", derive(Clone, Copy))]
 #[repr(C, align(16))]
@@ -21,9 +68,23 @@ pub struct RenderUniform {
     pub camera_rotation: [f32; 4],
     pub resolution: [u32; 2],
     pub target_image: ResourceHandle,
-    pub pad1: [u32; 2],
+    ///One of the [render_mode] constants.
+    pub render_mode: u32,
     pub offset: [f32; 3],
-    pub pad2: f32,
+    ///Distance after which a ray counts as miss.
+    pub max_t: f32,
+    ///Distance to the surface at which a ray counts as hit.
+    pub epsilon: f32,
+    ///Step budget of a ray.
+    pub max_steps: u32,
+    ///Combination of the [flags] bits.
+    pub flags: u32,
+    ///Step scale ω of over-relaxed sphere tracing, in 1..2.
+    pub relaxation: f32,
+    ///Start distance per [CONE_TILE_SIZE] tile, written by the cone pre-pass.
+    pub start_image: ResourceHandle,
+    ///Size of the penumbra of soft shadows. Larger values give softer shadows.
+    pub shadow_softness: f32,
 }
 
 impl Default for RenderUniform {
@@ -34,13 +95,59 @@ impl Default for RenderUniform {
             camera_rotation: Quat::IDENTITY.into(),
             resolution: [100, 100],
             target_image: ResourceHandle::INVALID,
-            pad1: [0; 2],
+            render_mode: render_mode::SHADED,
             offset: [0.0; 3],
-            pad2: 0.0,
+            max_t: 150.0,
+            epsilon: 0.0001,
+            max_steps: 1_000_000,
+            flags: 0,
+            relaxation: 1.6,
+            start_image: ResourceHandle::INVALID,
+            shadow_softness: 0.1,
         }
     }
 }
 
+///Name, byte offset and size of each field of `$ty`'s default value, in declaration order.
+#[cfg(not(target_arch = "spirv"))]
+macro_rules! field_layout {
+    ($ty:ident { $($field:ident),* }) => {{
+        let value = $ty::default();
+        //NOTE: Fails to compile if a field is missing.
+        let $ty { $($field: _),* } = value;
+        let base = &value as *const $ty as usize;
+        [$((
+            stringify!($field),
+            core::ptr::addr_of!(value.$field) as usize - base,
+            core::mem::size_of_val(&value.$field),
+        )),*]
+    }};
+}
+
+#[cfg(not(target_arch = "spirv"))]
+impl RenderUniform {
+    ///Host side layout of the struct. Checked against the push constant block of the base shader, which is
+    /// generated by rust-gpu and might not match the manual padding.
+    pub fn field_layout() -> [(&'static str, usize, usize); 14] {
+        field_layout!(RenderUniform {
+            camera_pos,
+            fov,
+            camera_rotation,
+            resolution,
+            target_image,
+            render_mode,
+            offset,
+            max_t,
+            epsilon,
+            max_steps,
+            flags,
+            relaxation,
+            start_image,
+            shadow_softness
+        })
+    }
+}
+
 impl RenderUniform {
     fn aspect_ratio(&self) -> f32 {
         self.resolution[0] as f32 / self.resolution[1] as f32
@@ -52,6 +159,20 @@ impl RenderUniform {
             .mul_vec3(Vec3::Z)
             .normalize()
     }
+    ///Angle covered by a single pixel, in radians.
+    pub fn pixel_angle(&self) -> f32 {
+        2.0 * (self.fov / 2.0 * PI / 180.0).tan() / self.resolution[1] as f32
+    }
+
+    ///Surface epsilon at distance `t` along a ray. Grows with the pixel footprint if [flags::PIXEL_EPSILON] is set.
+    pub fn surface_epsilon(&self, t: f32) -> f32 {
+        if self.flags & flags::PIXEL_EPSILON != 0 {
+            self.epsilon.max(t * self.pixel_angle())
+        } else {
+            self.epsilon
+        }
+    }
+
     //ndc in -1.0 .. 1.0
     pub fn ray_from_ndc(&self, ndc: Vec2) -> Ray {
         let px = ndc.x * (self.fov / 2.0 * PI / 180.0).tan() * self.aspect_ratio();
@@ -63,7 +184,7 @@ impl RenderUniform {
 
         Ray {
             direction: direction.normalize(),
-            max_t: 150.0,
+            max_t: self.max_t,
             origin: self.camera_pos.into(),
         }
     }